@@ -4,10 +4,11 @@
 // This software may be used and distributed according to the terms of the
 // GNU General Public License version 2 or any later version.
 
-use std::collections::{BTreeMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::mem;
 use std::path::Path;
-use std::sync::Arc;
+use std::str;
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
 use ascii::AsciiString;
@@ -15,7 +16,7 @@ use bincode;
 use bytes::Bytes;
 use failure::{Fail, ResultExt};
 use futures::{Async, Poll};
-use futures::future::Future;
+use futures::future::{self, Future, Shared};
 use futures::stream::{self, Stream};
 use futures::sync::oneshot;
 use futures_ext::{BoxFuture, BoxStream, FutureExt, StreamExt};
@@ -28,6 +29,7 @@ use uuid::Uuid;
 use blobstore::Blobstore;
 use bookmarks::{self, Bookmarks};
 use changesets::{ChangesetInsert, Changesets, SqliteChangesets};
+use context::CoreContext;
 use dbbookmarks::SqliteDbBookmarks;
 use delayblob::DelayBlob;
 use dieselfilenodes::{SqliteFilenodes, DEFAULT_INSERT_CHUNK_SIZE};
@@ -37,8 +39,8 @@ use heads::Heads;
 use manifoldblob::ManifoldBlob;
 use memblob::EagerMemblob;
 use memheads::MemHeads;
-use mercurial_types::{Blob, BlobNode, Changeset, Entry, HgChangesetId, HgFileNodeId, Manifest,
-                      NodeHash, Parents, RepoPath, RepositoryId, Time};
+use mercurial_types::{Blob, BlobNode, Changeset, Content, Entry, HgChangesetId, HgFileNodeId,
+                      Manifest, NodeHash, Parents, RepoPath, RepositoryId, Time};
 use mercurial_types::manifest;
 use mercurial_types::nodehash::HgManifestId;
 use rocksblob::Rocksblob;
@@ -52,6 +54,167 @@ use file::{fetch_file_content_and_renames_from_blobstore, BlobEntry};
 use repo_commit::*;
 use utils::{get_node_key, RawNodeBlob};
 
+/// A single path's change in a `BonsaiChangeset`, relative to the changeset's parents. `None`
+/// (at the `file_changes` map level) means the path was removed. `file_type` here, like `extra`
+/// on `BonsaiChangeset`, keeps its native typed form rather than being frozen as a `Debug`
+/// string, so a reader can parse it back out instead of merely displaying it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct BonsaiFileChange {
+    content_id: String,
+    file_type: manifest::Type,
+    size: u64,
+    copy_from: Option<(String, String)>,
+}
+
+/// A backend-neutral, parent-relative view of a changeset: for every path touched relative to
+/// its parents, what changed about it. `create_changeset` derives this from the same root/parent
+/// manifests it diffs to build the Mercurial `BlobChangeset`'s file list, and stores it alongside
+/// that changeset under `bonsai:{cs_id}` so downstream tooling isn't tied to revlog semantics.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct BonsaiChangeset {
+    parents: Vec<String>,
+    author: String,
+    author_date: Time,
+    message: String,
+    extra: BTreeMap<Vec<u8>, Vec<u8>>,
+    file_changes: BTreeMap<String, Option<BonsaiFileChange>>,
+}
+
+/// A git-lfs-style pointer: a small, content-addressed-by-`oid` stand-in for a file's real
+/// content, which lives out-of-band (a real LFS backend, not this blobstore) under
+/// `lfs-content:{oid}`. The pointer itself is what gets hashed into the Mercurial file node and
+/// stored inline, exactly like any other file content; `get_file_content` recognizes the pointer
+/// format and transparently resolves it, so callers never need to know a file went through LFS.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LfsPointer {
+    pub oid: String,
+    pub size: u64,
+}
+
+impl LfsPointer {
+    const VERSION_LINE: &'static str = "version https://git-lfs.github.com/spec/v1";
+
+    fn to_bytes(&self) -> Bytes {
+        Bytes::from(format!(
+            "{}\noid sha256:{}\nsize {}\n",
+            Self::VERSION_LINE,
+            self.oid,
+            self.size
+        ))
+    }
+
+    fn from_bytes(bytes: &Bytes) -> Option<Self> {
+        let text = str::from_utf8(bytes).ok()?;
+        let mut lines = text.lines();
+        if lines.next()? != Self::VERSION_LINE {
+            return None;
+        }
+
+        let (mut oid, mut size) = (None, None);
+        for line in lines {
+            if line.starts_with("oid sha256:") {
+                oid = Some(line["oid sha256:".len()..].to_string());
+            } else if line.starts_with("size ") {
+                size = line["size ".len()..].parse::<u64>().ok();
+            }
+        }
+
+        Some(LfsPointer {
+            oid: oid?,
+            size: size?,
+        })
+    }
+
+    fn content_key(&self) -> String {
+        format!("lfs-content:{}", self.oid)
+    }
+}
+
+/// The content a file entry is uploaded with: either raw bytes to hash and store inline as
+/// before, or an `LfsPointer` plus the real (large) content it names, which gets stored
+/// out-of-band under `lfs-content:{oid}` - only the pointer itself is hashed into the Mercurial
+/// file node and stored inline.
+pub enum UploadHgFileContents {
+    RawBytes(Blob),
+    Lfs(LfsPointer, Bytes),
+}
+
+impl UploadHgFileContents {
+    /// Splits this into the `Blob` that should be hashed and stored inline as the file's own
+    /// content, and - for `Lfs` - the `(key, bytes)` of the out-of-band object `get_file_content`
+    /// will later resolve the pointer to.
+    fn into_blob(self) -> (Blob, Option<(String, Bytes)>) {
+        match self {
+            UploadHgFileContents::RawBytes(blob) => (blob, None),
+            UploadHgFileContents::Lfs(pointer, content) => {
+                let content_key = pointer.content_key();
+                (Blob::from(pointer.to_bytes()), Some((content_key, content)))
+            }
+        }
+    }
+}
+
+/// Builds and uploads a single Mercurial manifest (tree) entry. Returns the computed node id
+/// immediately, before the upload completes, so callers can wire up dependents (a parent tree,
+/// `create_changeset`'s root manifest future) without waiting on it - the same
+/// known-before-durable parallelism the old positional `upload_entry` offered.
+pub struct UploadHgTreeEntry {
+    pub contents: Blob,
+    pub p1: Option<NodeHash>,
+    pub p2: Option<NodeHash>,
+    pub path: RepoPath,
+}
+
+impl UploadHgTreeEntry {
+    pub fn upload(
+        self,
+        ctx: &CoreContext,
+        repo: &BlobRepo,
+    ) -> Result<(NodeHash, BoxFuture<(BlobEntry, RepoPath), Error>)> {
+        repo.upload_blob_entry(
+            ctx,
+            self.contents,
+            manifest::Type::Tree,
+            self.p1,
+            self.p2,
+            self.path,
+        )
+    }
+}
+
+/// Builds and uploads a single Mercurial file entry (file, executable, or symlink). See
+/// `UploadHgTreeEntry` for the upload/node-id-ordering contract this preserves.
+pub struct UploadHgFileEntry {
+    pub contents: UploadHgFileContents,
+    pub file_type: manifest::Type,
+    pub p1: Option<NodeHash>,
+    pub p2: Option<NodeHash>,
+    pub path: RepoPath,
+}
+
+impl UploadHgFileEntry {
+    pub fn upload(
+        self,
+        ctx: &CoreContext,
+        repo: &BlobRepo,
+    ) -> Result<(NodeHash, BoxFuture<(BlobEntry, RepoPath), Error>)> {
+        let (contents, lfs_content) = self.contents.into_blob();
+        let (nodeid, upload) =
+            repo.upload_blob_entry(ctx, contents, self.file_type, self.p1, self.p2, self.path)?;
+
+        let upload = match lfs_content {
+            Some((content_key, content)) => repo.blobstore
+                .put(ctx.clone(), content_key, content)
+                .join(upload)
+                .map(|(_, result)| result)
+                .boxify(),
+            None => upload,
+        };
+
+        Ok((nodeid, upload))
+    }
+}
+
 pub struct BlobRepo {
     logger: Logger,
     blobstore: Arc<Blobstore>,
@@ -60,6 +223,7 @@ pub struct BlobRepo {
     filenodes: Arc<Filenodes>,
     changesets: Arc<Changesets>,
     repoid: RepositoryId,
+    derivation_lease: Arc<DerivationLease>,
 }
 
 impl BlobRepo {
@@ -80,6 +244,7 @@ impl BlobRepo {
             filenodes,
             changesets,
             repoid,
+            derivation_lease: Arc::new(InProcessLease::new()),
         }
     }
 
@@ -209,15 +374,40 @@ impl BlobRepo {
         ))
     }
 
-    pub fn get_file_content(&self, key: &NodeHash) -> BoxFuture<Bytes, Error> {
+    pub fn get_file_content(&self, ctx: &CoreContext, key: &NodeHash) -> BoxFuture<Bytes, Error> {
+        ctx.log_scuba_sample("get_file_content", &format!("{}", key));
+        let blobstore = self.blobstore.clone();
+        let ctx = ctx.clone();
         fetch_file_content_and_renames_from_blobstore(&self.blobstore, *key)
             .map(|contentrename| contentrename.0)
+            .and_then(move |content| match LfsPointer::from_bytes(&content) {
+                // Transparently resolve LFS pointers: the inline blob is just a pointer, the
+                // real content lives out-of-band under the oid it names.
+                Some(pointer) => blobstore
+                    .get(ctx, pointer.content_key())
+                    .and_then(move |resolved| {
+                        resolved.ok_or_else(|| {
+                            Error::from(format!(
+                                "no out-of-band content stored for lfs oid {}",
+                                pointer.oid
+                            ))
+                        })
+                    })
+                    .boxify(),
+                None => future::ok(content).boxify(),
+            })
             .boxify()
     }
 
-    pub fn get_parents(&self, path: &RepoPath, node: &NodeHash) -> BoxFuture<Parents, Error> {
+    pub fn get_parents(
+        &self,
+        ctx: &CoreContext,
+        path: &RepoPath,
+        node: &NodeHash,
+    ) -> BoxFuture<Parents, Error> {
         let path = path.clone();
         let node = HgFileNodeId::new(*node);
+        ctx.log_scuba_sample("get_parents", &format!("{}", path));
         self.filenodes
             .get_filenode(&path, &node, &self.repoid)
             .and_then({
@@ -236,11 +426,13 @@ impl BlobRepo {
 
     pub fn get_file_copy(
         &self,
+        ctx: &CoreContext,
         path: &RepoPath,
         node: &NodeHash,
     ) -> BoxFuture<Option<(RepoPath, NodeHash)>, Error> {
         let path = path.clone();
         let node = HgFileNodeId::new(*node);
+        ctx.log_scuba_sample("get_file_copy", &format!("{}", path));
         self.filenodes
             .get_filenode(&path, &node, &self.repoid)
             .and_then({
@@ -257,8 +449,9 @@ impl BlobRepo {
             .boxify()
     }
 
-    pub fn get_changesets(&self) -> BoxStream<NodeHash, Error> {
+    pub fn get_changesets(&self, ctx: &CoreContext) -> BoxStream<NodeHash, Error> {
         BlobChangesetStream {
+            ctx: ctx.clone(),
             repo: self.clone(),
             heads: self.heads.heads().boxify(),
             state: BCState::Idle,
@@ -266,11 +459,17 @@ impl BlobRepo {
         }.boxify()
     }
 
-    pub fn get_heads(&self) -> BoxStream<NodeHash, Error> {
+    pub fn get_heads(&self, ctx: &CoreContext) -> BoxStream<NodeHash, Error> {
+        ctx.log_scuba_sample("get_heads", "");
         self.heads.heads().boxify()
     }
 
-    pub fn changeset_exists(&self, changesetid: &HgChangesetId) -> BoxFuture<bool, Error> {
+    pub fn changeset_exists(
+        &self,
+        ctx: &CoreContext,
+        changesetid: &HgChangesetId,
+    ) -> BoxFuture<bool, Error> {
+        ctx.log_scuba_sample("changeset_exists", &format!("{}", changesetid));
         self.changesets
             .get(self.repoid, *changesetid)
             .map(|res| res.is_some())
@@ -279,9 +478,11 @@ impl BlobRepo {
 
     pub fn get_changeset_by_changesetid(
         &self,
+        ctx: &CoreContext,
         changesetid: &HgChangesetId,
     ) -> BoxFuture<BlobChangeset, Error> {
         let chid = changesetid.clone();
+        ctx.log_scuba_sample("get_changeset_by_changesetid", &format!("{}", chid));
         BlobChangeset::load(&self.blobstore, &chid)
             .and_then(move |cs| cs.ok_or(ErrorKind::ChangesetMissing(chid).into()))
             .boxify()
@@ -289,31 +490,85 @@ impl BlobRepo {
 
     pub fn get_manifest_by_nodeid(
         &self,
+        ctx: &CoreContext,
         nodeid: &NodeHash,
     ) -> BoxFuture<Box<Manifest + Sync>, Error> {
         let nodeid = *nodeid;
         let manifestid = HgManifestId::new(nodeid);
+        ctx.log_scuba_sample("get_manifest_by_nodeid", &format!("{}", nodeid));
         BlobManifest::load(&self.blobstore, &manifestid)
             .and_then(move |mf| mf.ok_or(ErrorKind::ManifestMissing(nodeid).into()))
             .map(|m| m.boxed())
             .boxify()
     }
 
-    pub fn get_root_entry(&self, manifestid: &HgManifestId) -> Box<Entry + Sync> {
+    pub fn get_root_entry(
+        &self,
+        ctx: &CoreContext,
+        manifestid: &HgManifestId,
+    ) -> Box<Entry + Sync> {
+        ctx.log_scuba_sample("get_root_entry", &format!("{}", manifestid));
         Box::new(BlobEntry::new_root(self.blobstore.clone(), *manifestid))
     }
 
-    pub fn get_bookmarks(&self) -> BoxStream<(AsciiString, HgChangesetId), Error> {
+    /// Return `cs_id`'s already-derived `T`, deriving it (and as many of its ancestors as
+    /// necessary) first if it isn't cached yet. Walks back through parents recursively, so the
+    /// cost of a `derive` call is proportional to the distance to the nearest ancestor that
+    /// already has `T` derived, not to the full history.
+    pub fn derive<T: DerivedData>(
+        &self,
+        ctx: &CoreContext,
+        cs_id: HgChangesetId,
+    ) -> BoxFuture<T, Error> {
+        derive_recursive::<T>(
+            self.clone(),
+            ctx.clone(),
+            self.derivation_lease.clone(),
+            Arc::new(Mutex::new(HashMap::new())),
+            cs_id.into_nodehash(),
+        )
+    }
+
+    /// Derive `T` for every changeset `get_changesets` yields, with up to `PARALLELISM`
+    /// derivations in flight at once. Intended for backfilling a new `DerivedData` over an
+    /// existing repo; safe to interrupt and re-run, since `derive` is idempotent and skips
+    /// changesets that already have `T`.
+    pub fn backfill_derived_data<T: DerivedData>(&self, ctx: &CoreContext) -> BoxFuture<(), Error> {
+        const PARALLELISM: usize = 100;
+        let repo = self.clone();
+        let ctx = ctx.clone();
+
+        self.get_changesets(&ctx)
+            .map(move |node| {
+                repo.derive::<T>(&ctx, HgChangesetId::new(node))
+            })
+            .buffer_unordered(PARALLELISM)
+            .for_each(|_| Ok(()))
+            .boxify()
+    }
+
+    pub fn get_bookmarks(
+        &self,
+        ctx: &CoreContext,
+    ) -> BoxStream<(AsciiString, HgChangesetId), Error> {
+        ctx.log_scuba_sample("get_bookmarks", "");
         let empty_prefix = AsciiString::new();
         self.bookmarks.list_by_prefix(&empty_prefix, &self.repoid)
     }
 
-    pub fn update_bookmark_transaction(&self) -> Box<bookmarks::Transaction> {
+    pub fn update_bookmark_transaction(&self, ctx: &CoreContext) -> Box<bookmarks::Transaction> {
+        ctx.log_scuba_sample("update_bookmark_transaction", "");
         self.bookmarks.create_transaction(&self.repoid)
     }
 
-    pub fn get_linknode(&self, path: RepoPath, node: &NodeHash) -> BoxFuture<NodeHash, Error> {
+    pub fn get_linknode(
+        &self,
+        ctx: &CoreContext,
+        path: RepoPath,
+        node: &NodeHash,
+    ) -> BoxFuture<NodeHash, Error> {
         let node = HgFileNodeId::new(*node);
+        ctx.log_scuba_sample("get_linknode", &format!("{}", path));
         self.filenodes
             .get_filenode(&path, &node, &self.repoid)
             .and_then({
@@ -326,7 +581,12 @@ impl BlobRepo {
             .boxify()
     }
 
-    pub fn get_generation_number(&self, cs: &HgChangesetId) -> BoxFuture<Option<u64>, Error> {
+    pub fn get_generation_number(
+        &self,
+        ctx: &CoreContext,
+        cs: &HgChangesetId,
+    ) -> BoxFuture<Option<u64>, Error> {
+        ctx.log_scuba_sample("get_generation_number", &format!("{}", cs));
         self.changesets
             .get(self.repoid, *cs)
             .map(|res| res.map(|res| res.gen))
@@ -340,14 +600,19 @@ impl BlobRepo {
     // point, as long as you know their NodeHashes; this is also given to you as part of the
     // result type, so that you can parallelise uploads. Consistency will be verified when
     // adding the entries to a changeset.
-    pub fn upload_entry(
+    //
+    // This is the shared primitive behind `UploadHgTreeEntry` and `UploadHgFileEntry`; callers
+    // should go through those rather than calling this directly.
+    fn upload_blob_entry(
         &self,
+        ctx: &CoreContext,
         raw_content: Blob,
         content_type: manifest::Type,
         p1: Option<NodeHash>,
         p2: Option<NodeHash>,
         path: RepoPath,
     ) -> Result<(NodeHash, BoxFuture<(BlobEntry, RepoPath), Error>)> {
+        let ctx = ctx.clone();
         let p1 = p1.as_ref();
         let p2 = p2.as_ref();
         let raw_content = raw_content.clean();
@@ -376,7 +641,7 @@ impl BlobRepo {
         )?;
 
         fn log_upload_stats(
-            logger: Logger,
+            ctx: &CoreContext,
             path: RepoPath,
             nodeid: NodeHash,
             phase: &str,
@@ -384,7 +649,8 @@ impl BlobRepo {
         ) {
             let path = format!("{}", path);
             let nodeid = format!("{}", nodeid);
-            debug!(logger, "Upload blob stats";
+            debug!(ctx.logger(), "Upload blob stats";
+                "session_uuid" => format!("{}", ctx.session()),
                 "phase" => String::from(phase),
                 "path" => path,
                 "nodeid" => nodeid,
@@ -395,8 +661,10 @@ impl BlobRepo {
         }
 
         // Ensure that content is in the blobstore
+        ctx.log_scuba_sample("upload_blob_entry", &format!("{}", nodeid));
         let content_upload = self.blobstore
             .put(
+                ctx.clone(),
                 format!("sha1-{}", blob_hash.sha1()),
                 raw_content
                     .clone()
@@ -404,18 +672,19 @@ impl BlobRepo {
                     .ok_or_else(|| Error::from(ErrorKind::BadUploadBlob(raw_content.clone())))?,
             )
             .timed({
-                let logger = self.logger.clone();
+                let ctx = ctx.clone();
                 let path = path.clone();
                 let nodeid = nodeid.clone();
                 move |stats, result| {
                     if result.is_ok() {
-                        log_upload_stats(logger, path, nodeid, "content_uploaded", stats)
+                        log_upload_stats(&ctx, path, nodeid, "content_uploaded", stats)
                     }
                     Ok(())
                 }
             });
         // Upload the new node
         let node_upload = self.blobstore.put(
+            ctx.clone(),
             get_node_key(nodeid),
             bincode::serialize(&raw_node)
                 .map_err(|err| Error::from(ErrorKind::SerializationFailed(nodeid, err)))?
@@ -431,12 +700,11 @@ impl BlobRepo {
                     |_| (blob_entry, path)
                 })
                 .timed({
-                    let logger = self.logger.clone();
                     let path = path.clone();
                     let nodeid = nodeid.clone();
                     move |stats, result| {
                         if result.is_ok() {
-                            log_upload_stats(logger, path, nodeid, "finished", stats)
+                            log_upload_stats(&ctx, path, nodeid, "finished", stats)
                         }
                         Ok(())
                     }
@@ -450,6 +718,7 @@ impl BlobRepo {
     /// No attempt is made to clean up the Blobstore if the changeset creation fails
     pub fn create_changeset(
         &self,
+        ctx: &CoreContext,
         p1: Option<ChangesetHandle>,
         p2: Option<ChangesetHandle>,
         root_manifest: BoxFuture<(BlobEntry, RepoPath), Error>,
@@ -464,6 +733,25 @@ impl BlobRepo {
         // This is used for logging, so that we can tie up all our pieces without knowing about
         // the final commit hash
         let uuid = Uuid::new_v4();
+        ctx.log_scuba_sample("create_changeset", &format!("{}", uuid));
+
+        // `new_child_entries` only carries paths that are new or changed relative to the
+        // parents; tee it into `present_files` as it drains so the bonsai derivation below can
+        // tell a "changed" path from a "removed" one without re-walking the manifests itself.
+        let present_files: Arc<Mutex<BTreeMap<String, (NodeHash, manifest::Type)>>> =
+            Arc::new(Mutex::new(BTreeMap::new()));
+        let new_child_entries = {
+            let present_files = present_files.clone();
+            new_child_entries
+                .map(move |(entry, path)| {
+                    present_files
+                        .lock()
+                        .expect("present_files lock poisoned")
+                        .insert(format!("{}", path), (*entry.get_hash(), entry.get_type()));
+                    (entry, path)
+                })
+                .boxify()
+        };
 
         let upload_entries = process_entries(
             self.logger.clone(),
@@ -481,10 +769,12 @@ impl BlobRepo {
             upload_entries
                 .join(parents_data)
                 .and_then({
+                    let repo = self.clone();
                     let filenodes = self.filenodes.clone();
                     let blobstore = self.blobstore.clone();
                     let heads = self.heads.clone();
                     let logger = self.logger.clone();
+                    let ctx = ctx.clone();
 
                     move |((root_manifest, root_hash), (parents, p1_manifest, p2_manifest))| {
                         compute_changed_files(
@@ -493,6 +783,12 @@ impl BlobRepo {
                             p2_manifest.as_ref(),
                         ).and_then({
                             move |files| {
+                                let author = user.clone();
+                                let author_date = time.clone();
+                                let message = comments.clone();
+                                let extra_for_bonsai = extra.clone();
+                                let touched_paths = files.clone();
+
                                 let blobcs = try_boxfuture!(make_new_changeset(
                                     parents,
                                     root_hash,
@@ -505,15 +801,49 @@ impl BlobRepo {
 
                                 let cs_id = blobcs.get_changeset_id().into_nodehash();
                                 let manifest_id = *blobcs.manifestid();
+                                let bonsai_parents = blobcs
+                                    .parents()
+                                    .into_iter()
+                                    .map(|n| format!("{}", n))
+                                    .collect();
 
                                 debug!(logger, "Changeset uuid to hash mapping";
                                     "changeset_uuid" => format!("{}", uuid),
                                     "changeset_id" => format!("{}", cs_id));
 
+                                let present_files = present_files
+                                    .lock()
+                                    .expect("present_files lock poisoned")
+                                    .clone();
+                                let bonsai = repo.derive_bonsai_changeset(
+                                    &ctx,
+                                    cs_id,
+                                    bonsai_parents,
+                                    author,
+                                    author_date,
+                                    message,
+                                    extra_for_bonsai,
+                                    touched_paths,
+                                    present_files,
+                                );
+
                                 blobcs
                                     .save(blobstore)
                                     .join(heads.add(&cs_id))
+                                    // NOT WIRED UP: `entry_processor.finalize` below still does a
+                                    // full-tree check. `find_intersection_of_diffs` (below in this
+                                    // file) computes the set chunk1-3 wants `finalize` restricted
+                                    // to, but `UploadEntries::finalize` lives in `repo_commit`,
+                                    // which isn't part of this snapshot, so the call site can't
+                                    // actually be switched over from here: `finalize` only takes
+                                    // `(filenodes, cs_id)` today, and adding a third argument here
+                                    // without `repo_commit`'s signature growing to accept it would
+                                    // not compile. chunk1-3's behavior change - "only check this
+                                    // commit's new entries" - is therefore deferred, not done;
+                                    // treat it as still open until `repo_commit` is available to
+                                    // edit.
                                     .join(entry_processor.finalize(filenodes, cs_id))
+                                    .join(bonsai)
                                     .map(move |_| {
                                         // We deliberately eat this error - this is only so that
                                         // another changeset can start uploading to the blob store
@@ -577,6 +907,92 @@ impl BlobRepo {
                 .shared(),
         )
     }
+
+    /// Derive the `BonsaiChangeset` for a changeset that's already been assigned `cs_id`, and
+    /// store it under `bonsai:{cs_id}`. `touched_paths` is every path in the Mercurial
+    /// changeset's file list (additions, modifications, and removals alike); `present_files` is
+    /// the subset of those that are still present in the new root manifest, keyed by path and
+    /// holding the uploaded entry's hash and type - paths from `touched_paths` missing from
+    /// `present_files` were removed relative to p1. Copy-from info comes from `get_file_copy`,
+    /// resolved to the originating changeset id via that source filenode's `get_linknode`.
+    fn derive_bonsai_changeset(
+        &self,
+        ctx: &CoreContext,
+        cs_id: NodeHash,
+        parents: Vec<String>,
+        author: String,
+        author_date: Time,
+        message: String,
+        extra: BTreeMap<Vec<u8>, Vec<u8>>,
+        touched_paths: Vec<RepoPath>,
+        present_files: BTreeMap<String, (NodeHash, manifest::Type)>,
+    ) -> BoxFuture<(), Error> {
+        let repo = self.clone();
+        let ctx = ctx.clone();
+        let ctx_for_store = ctx.clone();
+        let blobstore = self.blobstore.clone();
+
+        let changes = touched_paths.into_iter().map(move |path| {
+            let path_str = format!("{}", path);
+            match present_files.get(&path_str).cloned() {
+                Some((node, file_type)) => {
+                    let size = repo.get_file_content(&ctx, &node).map(|blob| blob.len() as u64);
+                    let copy_from = {
+                        let repo = repo.clone();
+                        let ctx = ctx.clone();
+                        repo.get_file_copy(&ctx, &path, &node).and_then(move |copy| {
+                            match copy {
+                                Some((from_path, from_node)) => repo.get_linknode(
+                                    &ctx,
+                                    from_path.clone(),
+                                    &from_node,
+                                ).map(move |linknode| {
+                                        Some((format!("{}", from_path), format!("{}", linknode)))
+                                    })
+                                    .boxify(),
+                                None => future::ok(None).boxify(),
+                            }
+                        })
+                    };
+
+                    size.join(copy_from)
+                        .map(move |(size, copy_from)| {
+                            (
+                                path_str,
+                                Some(BonsaiFileChange {
+                                    content_id: format!("{}", node),
+                                    file_type,
+                                    size,
+                                    copy_from,
+                                }),
+                            )
+                        })
+                        .boxify()
+                }
+                None => future::ok((path_str, None)).boxify(),
+            }
+        });
+
+        future::join_all(changes)
+            .map(move |changes| BonsaiChangeset {
+                parents,
+                author,
+                author_date,
+                message,
+                extra,
+                file_changes: changes.into_iter().collect(),
+            })
+            .and_then(move |bonsai| {
+                let serialized = try_boxfuture!(
+                    bincode::serialize(&bonsai)
+                        .map_err(|err| Error::from(ErrorKind::SerializationFailed(cs_id, err)))
+                );
+                blobstore
+                    .put(ctx_for_store, format!("bonsai:{}", cs_id), serialized.into())
+                    .boxify()
+            })
+            .boxify()
+    }
 }
 
 impl Clone for BlobRepo {
@@ -589,11 +1005,13 @@ impl Clone for BlobRepo {
             filenodes: self.filenodes.clone(),
             changesets: self.changesets.clone(),
             repoid: self.repoid.clone(),
+            derivation_lease: self.derivation_lease.clone(),
         }
     }
 }
 
 pub struct BlobChangesetStream {
+    ctx: CoreContext,
     repo: BlobRepo,
     seen: HashSet<NodeHash>,
     heads: BoxStream<NodeHash, Error>,
@@ -620,8 +1038,10 @@ impl Stream for BlobChangesetStream {
                             // haven't seen before
                             WaitCS(
                                 next,
-                                self.repo
-                                    .get_changeset_by_changesetid(&HgChangesetId::new(next)),
+                                self.repo.get_changeset_by_changesetid(
+                                    &self.ctx,
+                                    &HgChangesetId::new(next),
+                                ),
                             )
                         } else {
                             Idle // already done it
@@ -656,3 +1076,436 @@ impl Stream for BlobChangesetStream {
         }
     }
 }
+
+/// Recursively walk every entry reachable from `manifest`, yielding a `(path, node)` pair for
+/// each file, symlink, executable, and subtree it (transitively) contains.
+fn walk_manifest(manifest: Box<Manifest + Sync>) -> BoxStream<(RepoPath, NodeHash), Error> {
+    manifest
+        .list()
+        .map(|entry| -> BoxStream<(RepoPath, NodeHash), Error> {
+            let path = entry.get_path().clone();
+            let node = *entry.get_hash();
+            let here = stream::once(Ok((path, node)));
+
+            match entry.get_type() {
+                manifest::Type::Tree => entry
+                    .get_content()
+                    .map(|content| match content {
+                        Content::Tree(sub) => walk_manifest(sub),
+                        _ => stream::empty().boxify(),
+                    })
+                    .flatten_stream()
+                    .chain(here)
+                    .boxify(),
+                _ => here.boxify(),
+            }
+        })
+        .flatten()
+        .boxify()
+}
+
+/// Compute the set of `(path, node)` pairs that `root` introduces relative to `parents`: every
+/// entry reachable from `root` whose `(path, node)` pair isn't already reachable from at least
+/// one parent manifest. `UploadEntries::finalize` should use this to limit its blobstore
+/// presence check to the entries a commit actually adds - anything else must already have been
+/// verified when the parent that contributed it was itself finalized - and fall back to a full
+/// walk of `root` when `parents` is empty.
+///
+/// TODO(chunk1-3): `UploadEntries::finalize` lives in `repo_commit`, which isn't part of this
+/// snapshot, so `create_changeset`'s call to it can't be switched over to this helper here; it
+/// still does a full-tree check. This is exposed so that call site (and any other future one)
+/// can adopt it once `repo_commit` is available to edit.
+pub fn find_intersection_of_diffs(
+    root: Box<Manifest + Sync>,
+    parents: Vec<Box<Manifest + Sync>>,
+) -> BoxFuture<HashSet<(RepoPath, NodeHash)>, Error> {
+    if parents.is_empty() {
+        return walk_manifest(root).collect().map(|v| v.into_iter().collect()).boxify();
+    }
+
+    let parent_entries = future::join_all(parents.into_iter().map(|p| walk_manifest(p).collect()));
+
+    walk_manifest(root)
+        .collect()
+        .join(parent_entries)
+        .map(|(root_entries, parent_entry_lists)| {
+            let seen: HashSet<(RepoPath, NodeHash)> = parent_entry_lists
+                .into_iter()
+                .flat_map(|entries| entries.into_iter())
+                .collect();
+            root_entries
+                .into_iter()
+                .filter(|entry| !seen.contains(entry))
+                .collect()
+        })
+        .boxify()
+}
+
+/// A secondary index over this repo's changesets that can be computed purely from a changeset's
+/// Bonsai form and its parents' already-derived values, and is worth caching in the blobstore
+/// rather than recomputing on every read. See `BlobRepo::derive` and `backfill_derived_data`.
+pub trait DerivedData: Sized + Clone + Send + Sync + 'static {
+    /// Stable name used as this data's blobstore key prefix (`"{NAME}:{cs_id}"`) and lease name;
+    /// must be unique among the `DerivedData` types sharing a repo.
+    const NAME: &'static str;
+
+    /// Compute this changeset's value from its Bonsai changeset and its parents' values, in the
+    /// same order as `bonsai.parents`.
+    fn derive_from_parents(
+        ctx: CoreContext,
+        repo: BlobRepo,
+        cs_id: NodeHash,
+        bonsai: BonsaiChangeset,
+        parents: Vec<Self>,
+    ) -> BoxFuture<Self, Error>;
+
+    fn serialize(&self) -> Result<Bytes>;
+    fn deserialize(bytes: Bytes) -> Result<Self>;
+}
+
+/// Tracks which `(DerivedData::NAME, cs_id)` keys have a derivation in progress. A real
+/// deployment would back this with a distributed advisory lock (e.g. a MySQL `GET_LOCK`).
+/// `derive_recursive` does not act on the result of `try_acquire`: a caller that finds the key
+/// already held derives locally anyway and releases the key when it finishes, so concurrent
+/// callers for the same key can each compute the value.
+pub trait DerivationLease: Send + Sync {
+    fn try_acquire(&self, key: &str) -> BoxFuture<bool, Error>;
+    fn release(&self, key: &str);
+}
+
+/// An in-process `DerivationLease`, sufficient for a single `BlobRepo` instance. Does not
+/// coordinate across processes.
+pub struct InProcessLease {
+    held: Mutex<HashSet<String>>,
+}
+
+impl InProcessLease {
+    pub fn new() -> Self {
+        InProcessLease {
+            held: Mutex::new(HashSet::new()),
+        }
+    }
+}
+
+impl DerivationLease for InProcessLease {
+    fn try_acquire(&self, key: &str) -> BoxFuture<bool, Error> {
+        let acquired = self.held
+            .lock()
+            .expect("lease lock poisoned")
+            .insert(key.to_string());
+        future::ok(acquired).boxify()
+    }
+
+    fn release(&self, key: &str) {
+        self.held.lock().expect("lease lock poisoned").remove(key);
+    }
+}
+
+fn parse_nodehash(s: &str) -> Result<NodeHash> {
+    s.parse::<NodeHash>()
+        .map_err(|_| Error::from(format!("invalid node hash: {}", s)))
+}
+
+/// Per-top-level-`BlobRepo::derive`-call memoization, keyed by `cs_id` (a single call is always
+/// for one `T`, so `T::NAME` doesn't need to be part of the key). Without this, a diamond in
+/// history - two changesets both descending from a common ancestor - would recurse into that
+/// ancestor once per path that reaches it via `future::join_all`, nesting futures proportional to
+/// history depth instead of sharing the one derivation both paths need.
+type DeriveCache<T> = Arc<Mutex<HashMap<NodeHash, Shared<BoxFuture<T, Arc<Error>>>>>>;
+
+fn shared_to_boxfuture<T: Clone + Send + 'static>(shared: Shared<BoxFuture<T, Arc<Error>>>) -> BoxFuture<T, Error> {
+    shared
+        .map(|item| (*item).clone())
+        .map_err(|err| Error::from(format!("{}", err)))
+        .boxify()
+}
+
+/// Ensure `T` is derived for `cs_id`, recursing into parents (via their stored Bonsai changeset)
+/// as needed and stopping as soon as an already-derived ancestor - or a root changeset - is
+/// reached. See `BlobRepo::derive`.
+fn derive_recursive<T: DerivedData>(
+    repo: BlobRepo,
+    ctx: CoreContext,
+    lease: Arc<DerivationLease>,
+    cache: DeriveCache<T>,
+    cs_id: NodeHash,
+) -> BoxFuture<T, Error> {
+    if let Some(shared) = cache.lock().expect("derive cache lock poisoned").get(&cs_id).cloned() {
+        return shared_to_boxfuture(shared);
+    }
+
+    let key = format!("{}:{}", T::NAME, cs_id);
+    let blobstore = repo.blobstore.clone();
+    let ctx_for_get = ctx.clone();
+
+    let derivation: BoxFuture<T, Error> = blobstore
+        .get(ctx_for_get, key.clone())
+        .and_then(move |existing| -> BoxFuture<T, Error> {
+            if let Some(bytes) = existing {
+                return future::result(T::deserialize(bytes)).boxify();
+            }
+
+            let bonsai_key = format!("bonsai:{}", cs_id);
+            let repo_for_bonsai = repo.clone();
+            let repo_for_derive = repo.clone();
+            let repo_for_store = repo.clone();
+            let ctx_for_bonsai = ctx.clone();
+            let ctx_for_derive = ctx.clone();
+            let ctx_for_store = ctx.clone();
+            let lease_for_parents = lease.clone();
+            let lease_for_release = lease.clone();
+            let cache_for_parents = cache.clone();
+            let store_key = key.clone();
+            let release_key = key.clone();
+
+            lease
+                .try_acquire(&key)
+                .and_then(move |_acquired| {
+                    repo_for_bonsai.blobstore.get(ctx_for_bonsai, bonsai_key).and_then(move |bytes| {
+                        let bytes = bytes.ok_or_else(|| {
+                            Error::from(format!("no bonsai changeset stored for {}", cs_id))
+                        })?;
+                        let bonsai: BonsaiChangeset = bincode::deserialize(&bytes)
+                            .map_err(|err| Error::from(format!("{}", err)))?;
+                        Ok(bonsai)
+                    })
+                })
+                .and_then(move |bonsai| {
+                    let parent_ids = try_boxfuture!(
+                        bonsai
+                            .parents
+                            .iter()
+                            .map(|p| parse_nodehash(p))
+                            .collect::<Result<Vec<_>>>()
+                    );
+
+                    future::join_all(parent_ids.into_iter().map(move |pid| {
+                        derive_recursive::<T>(
+                            repo_for_derive.clone(),
+                            ctx_for_derive.clone(),
+                            lease_for_parents.clone(),
+                            cache_for_parents.clone(),
+                            pid,
+                        )
+                    })).and_then(move |parent_values| {
+                        T::derive_from_parents(ctx_for_derive, repo_for_derive, cs_id, bonsai, parent_values)
+                    })
+                        .boxify()
+                })
+                .and_then(move |value: T| {
+                    let serialized = try_boxfuture!(value.serialize());
+                    repo_for_store
+                        .blobstore
+                        .put(ctx_for_store, store_key, serialized)
+                        .map(move |_| value)
+                        .boxify()
+                })
+                // Release regardless of success or failure: a derivation that errors must not
+                // permanently wedge `try_acquire` for `key`, or every future `derive` call for it
+                // would incorrectly believe someone else is already working on it.
+                .then(move |result| {
+                    lease_for_release.release(&release_key);
+                    result
+                })
+                .boxify()
+        })
+        .boxify();
+
+    let shared = derivation.map_err(Arc::new).boxify().shared();
+    cache
+        .lock()
+        .expect("derive cache lock poisoned")
+        .insert(cs_id, shared.clone());
+
+    shared_to_boxfuture(shared)
+}
+
+/// Maps every path ever touched in this repo's history to the changeset that most recently
+/// touched it - a "unode" ("universal manifest node") index. The starting point for derived data
+/// that needs last-modifying-changeset info without walking blame history per query.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UnodeManifest {
+    last_touched: BTreeMap<String, String>,
+}
+
+impl DerivedData for UnodeManifest {
+    const NAME: &'static str = "unode";
+
+    fn derive_from_parents(
+        _ctx: CoreContext,
+        _repo: BlobRepo,
+        cs_id: NodeHash,
+        bonsai: BonsaiChangeset,
+        parents: Vec<Self>,
+    ) -> BoxFuture<Self, Error> {
+        let mut last_touched = BTreeMap::new();
+        for parent in parents {
+            last_touched.extend(parent.last_touched);
+        }
+
+        let here = format!("{}", cs_id);
+        for path in bonsai.file_changes.keys() {
+            last_touched.insert(path.clone(), here.clone());
+        }
+
+        future::ok(UnodeManifest { last_touched }).boxify()
+    }
+
+    fn serialize(&self) -> Result<Bytes> {
+        Ok(bincode::serialize(self)
+            .map_err(|err| Error::from(format!("{}", err)))?
+            .into())
+    }
+
+    fn deserialize(bytes: Bytes) -> Result<Self> {
+        bincode::deserialize(&bytes).map_err(|err| Error::from(format!("{}", err)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::RefCell;
+
+    use super::*;
+
+    #[test]
+    fn lfs_pointer_round_trips_through_get_file_content() {
+        let repo = BlobRepo::new_memblob_empty(None, None).expect("repo");
+        let ctx = CoreContext::new(Logger::root(Discard {}.ignore_res(), o!()));
+
+        let real_content = Bytes::from(&b"some file content too big to store inline"[..]);
+        let pointer = LfsPointer {
+            oid: "deadbeef".to_string(),
+            size: real_content.len() as u64,
+        };
+
+        let entry = UploadHgFileEntry {
+            contents: UploadHgFileContents::Lfs(pointer, real_content.clone()),
+            file_type: manifest::Type::File,
+            p1: None,
+            p2: None,
+            path: RepoPath::file("lfs-file").expect("valid path"),
+        };
+
+        let (nodeid, upload) = entry.upload(&ctx, &repo).expect("upload");
+        upload.wait().expect("upload future failed");
+
+        let fetched = repo.get_file_content(&ctx, &nodeid)
+            .wait()
+            .expect("get_file_content failed");
+        assert_eq!(fetched, real_content);
+    }
+
+    // Counts, per `cs_id`, how many times `derive_from_parents` actually ran - as opposed to
+    // being served from `DeriveCache` or the blobstore. Real `DerivedData` impls can't observe
+    // this themselves (`derive_from_parents` takes no `&self`), so the count lives in a
+    // thread-local instead; tests below run single-threaded via `.wait()`, so that's enough.
+    thread_local! {
+        static DERIVE_CALLS: RefCell<HashMap<NodeHash, usize>> = RefCell::new(HashMap::new());
+    }
+
+    #[derive(Clone)]
+    struct CountingDerivedData;
+
+    impl DerivedData for CountingDerivedData {
+        const NAME: &'static str = "counting";
+
+        fn derive_from_parents(
+            _ctx: CoreContext,
+            _repo: BlobRepo,
+            cs_id: NodeHash,
+            _bonsai: BonsaiChangeset,
+            _parents: Vec<Self>,
+        ) -> BoxFuture<Self, Error> {
+            DERIVE_CALLS.with(|calls| {
+                *calls.borrow_mut().entry(cs_id).or_insert(0) += 1;
+            });
+            future::ok(CountingDerivedData).boxify()
+        }
+
+        fn serialize(&self) -> Result<Bytes> {
+            Ok(Bytes::new())
+        }
+
+        fn deserialize(_bytes: Bytes) -> Result<Self> {
+            Ok(CountingDerivedData)
+        }
+    }
+
+    fn node(digit: char) -> NodeHash {
+        digit.to_string().repeat(40).parse().expect("valid node hash")
+    }
+
+    fn store_bonsai(repo: &BlobRepo, ctx: &CoreContext, cs_id: NodeHash, parents: Vec<NodeHash>) {
+        let bonsai = BonsaiChangeset {
+            parents: parents.iter().map(|p| format!("{}", p)).collect(),
+            author: "test".to_string(),
+            author_date: Time::default(),
+            message: "test".to_string(),
+            extra: BTreeMap::new(),
+            file_changes: BTreeMap::new(),
+        };
+        let serialized = Bytes::from(bincode::serialize(&bonsai).expect("bincode serialize"));
+        repo.blobstore
+            .put(ctx.clone(), format!("bonsai:{}", cs_id), serialized)
+            .wait()
+            .expect("store bonsai");
+    }
+
+    #[test]
+    fn derive_recursive_shares_a_diamonds_common_ancestor() {
+        let repo = BlobRepo::new_memblob_empty(None, None).expect("repo");
+        let ctx = CoreContext::new(Logger::root(Discard {}.ignore_res(), o!()));
+
+        let root = node('1');
+        let a = node('2');
+        let b = node('3');
+        let c = node('4');
+
+        store_bonsai(&repo, &ctx, root, vec![]);
+        store_bonsai(&repo, &ctx, a, vec![root]);
+        store_bonsai(&repo, &ctx, b, vec![root]);
+        store_bonsai(&repo, &ctx, c, vec![a, b]);
+
+        DERIVE_CALLS.with(|calls| calls.borrow_mut().clear());
+
+        repo.derive::<CountingDerivedData>(&ctx, HgChangesetId::new(c))
+            .wait()
+            .expect("derive failed");
+
+        DERIVE_CALLS.with(|calls| {
+            assert_eq!(
+                calls.borrow().get(&root).cloned(),
+                Some(1),
+                "root is reachable from both of c's parents - it must only be derived once"
+            );
+        });
+    }
+
+    #[test]
+    fn derive_recursive_releases_lease_after_a_failed_derivation() {
+        let repo = BlobRepo::new_memblob_empty(None, None).expect("repo");
+        let ctx = CoreContext::new(Logger::root(Discard {}.ignore_res(), o!()));
+        let lease: Arc<DerivationLease> = Arc::new(InProcessLease::new());
+
+        // No bonsai changeset stored for this node, so the derivation must fail when it tries
+        // to fetch it.
+        let missing = node('5');
+
+        let result = derive_recursive::<CountingDerivedData>(
+            repo.clone(),
+            ctx.clone(),
+            lease.clone(),
+            Arc::new(Mutex::new(HashMap::new())),
+            missing,
+        ).wait();
+        assert!(result.is_err(), "derivation with no stored bonsai changeset should fail");
+
+        let key = format!("{}:{}", CountingDerivedData::NAME, missing);
+        let reacquired = lease.try_acquire(&key).wait().expect("try_acquire");
+        assert!(
+            reacquired,
+            "a failed derivation must release its lease, or a retry would find it still held"
+        );
+    }
+}
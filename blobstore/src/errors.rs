@@ -0,0 +1,12 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+#[derive(Debug, Fail)]
+pub enum ErrorKind {
+    #[fail(display = "blobstore key not found: {}", _0)] NotFound(String),
+    #[fail(display = "link source key not found: {}", _0)] LinkSourceNotFound(String),
+    #[fail(display = "access to blobstore key {} is censored: {}", _0, _1)] Censored(String, String),
+}
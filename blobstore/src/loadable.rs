@@ -0,0 +1,185 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! `Loadable`/`Storable`: the pair of traits a blob-backed type implements to fetch itself out
+//! of a `Blobstore` by key, and to write itself in and hand back the key it landed under.
+//! `impl_loadable_storable!` generates both from a handful of small functions instead of making
+//! every new blob-backed type hand-write the fetch/deserialize and serialize/key-compute bodies.
+
+use failure::Error;
+use futures_ext::BoxFuture;
+
+use {Blobstore, CoreContext};
+
+#[derive(Debug, Fail)]
+pub enum LoadableError {
+    #[fail(display = "{}", _0)] Error(Error),
+    #[fail(display = "blob is missing: {}", _0)] Missing(String),
+}
+
+impl From<Error> for LoadableError {
+    fn from(err: Error) -> Self {
+        LoadableError::Error(err)
+    }
+}
+
+pub trait Loadable: Sized + 'static {
+    type Value;
+
+    fn load<B: Blobstore + Clone>(
+        &self,
+        ctx: CoreContext,
+        blobstore: &B,
+    ) -> BoxFuture<Self::Value, LoadableError>;
+}
+
+pub trait Storable: Sized + 'static {
+    type Key;
+
+    fn store<B: Blobstore + Clone>(
+        self,
+        ctx: CoreContext,
+        blobstore: &B,
+    ) -> BoxFuture<Self::Key, Error>;
+}
+
+/// Not part of this crate's public API; exists so `impl_loadable_storable!`'s expansion has
+/// somewhere to reach for the traits and futures plumbing it needs without every downstream
+/// crate that invokes the macro having to import them by hand.
+pub mod private {
+    pub use failure::Error;
+    pub use futures;
+    pub use futures::future::Future;
+    pub use futures_ext::{BoxFuture, FutureExt};
+
+    pub use context::CoreContext;
+
+    pub use {Blobstore, Loadable, LoadableError, Storable};
+}
+
+/// Generates `Loadable` for `$key` and `Storable` for `$value`, given:
+/// - `key_from_value`: `Fn(&$value) -> $key`, to name the key a freshly stored value lands under
+/// - `blobstore_key`: `Fn(&$key) -> String`, the blobstore string key a `$key` resolves to
+/// - `serialize`: `Fn(&$value) -> Result<Bytes, Error>`
+/// - `deserialize`: `Fn(Bytes) -> Result<$value, Error>`
+#[macro_export]
+macro_rules! impl_loadable_storable {
+    (
+        value: $value:ty,
+        key: $key:ty,
+        key_from_value: $key_from_value:expr,
+        blobstore_key: $blobstore_key:expr,
+        serialize: $serialize:expr,
+        deserialize: $deserialize:expr,
+    ) => {
+        impl $crate::private::Loadable for $key {
+            type Value = $value;
+
+            fn load<B: $crate::private::Blobstore + Clone>(
+                &self,
+                ctx: $crate::private::CoreContext,
+                blobstore: &B,
+            ) -> $crate::private::BoxFuture<Self::Value, $crate::private::LoadableError> {
+                use $crate::private::Future;
+                use $crate::private::FutureExt;
+
+                let blobstore_key = $blobstore_key(self);
+                let missing_key = blobstore_key.clone();
+                blobstore
+                    .get(ctx, blobstore_key)
+                    .from_err()
+                    .and_then(move |bytes| match bytes {
+                        Some(bytes) => $deserialize(bytes).map_err($crate::private::LoadableError::from),
+                        None => Err($crate::private::LoadableError::Missing(missing_key)),
+                    })
+                    .boxify()
+            }
+        }
+
+        impl $crate::private::Storable for $value {
+            type Key = $key;
+
+            fn store<B: $crate::private::Blobstore + Clone>(
+                self,
+                ctx: $crate::private::CoreContext,
+                blobstore: &B,
+            ) -> $crate::private::BoxFuture<Self::Key, $crate::private::Error> {
+                use $crate::private::Future;
+                use $crate::private::FutureExt;
+
+                let key = $key_from_value(&self);
+                let blobstore_key = $blobstore_key(&key);
+                let bytes = match $serialize(&self) {
+                    Ok(bytes) => bytes,
+                    Err(err) => return $crate::private::futures::future::err(err).boxify(),
+                };
+                blobstore
+                    .put(ctx, blobstore_key, bytes)
+                    .map(move |()| key)
+                    .boxify()
+            }
+        }
+    };
+}
+
+#[cfg(test)]
+mod test {
+    use bytes::Bytes;
+    use futures::Future;
+    use slog::{Discard, Drain, Logger};
+
+    use {ConfigStore, MemBlobstore};
+
+    use super::*;
+
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    struct TestKey(String);
+
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    struct TestValue(String);
+
+    impl_loadable_storable! {
+        value: TestValue,
+        key: TestKey,
+        key_from_value: |v: &TestValue| TestKey(v.0.clone()),
+        blobstore_key: |k: &TestKey| format!("test:{}", k.0),
+        serialize: |v: &TestValue| -> Result<Bytes, Error> { Ok(Bytes::from(v.0.clone())) },
+        deserialize: |bytes: Bytes| -> Result<TestValue, Error> {
+            Ok(TestValue(String::from_utf8(bytes.to_vec())?))
+        },
+    }
+
+    fn ctx() -> CoreContext {
+        CoreContext::new(Logger::root(Discard {}.ignore_res(), o!()))
+    }
+
+    fn store() -> MemBlobstore {
+        MemBlobstore::new(ConfigStore::new(100, 0))
+    }
+
+    #[test]
+    fn value_round_trips_through_store_and_load() {
+        let store = store();
+        let value = TestValue("hello".to_string());
+
+        let key = value.clone().store(ctx(), &store).wait().expect("store failed");
+        assert_eq!(key, TestKey("hello".to_string()));
+
+        let loaded = key.load(ctx(), &store).wait().expect("load failed");
+        assert_eq!(loaded, value);
+    }
+
+    #[test]
+    fn load_of_missing_key_returns_missing_error() {
+        let store = store();
+        let key = TestKey("absent".to_string());
+
+        match key.load(ctx(), &store).wait() {
+            Err(LoadableError::Missing(_)) => {}
+            other => panic!("expected Missing, got {:?}", other),
+        }
+    }
+}
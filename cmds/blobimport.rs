@@ -5,6 +5,7 @@
 // GNU General Public License version 2 or any later version.
 
 extern crate clap;
+extern crate ctrlc;
 extern crate futures;
 #[macro_use]
 extern crate error_chain;
@@ -31,15 +32,16 @@ extern crate serde;
 
 extern crate bincode;
 
-use std::collections::HashSet;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::io;
 use std::path::{Path, PathBuf};
-use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
 
 use bytes::Bytes;
 
 use futures::{Future, IntoFuture, Stream};
-use futures::future::BoxFuture;
+use futures::future::{BoxFuture, Shared};
 use futures::stream::BoxStream;
 use futures_ext::StreamExt;
 
@@ -58,7 +60,7 @@ use heads::Heads;
 use blobrepo::BlobChangeset;
 
 use mercurial::{RevlogManifest, RevlogRepo};
-use mercurial_types::{hash, Changeset, NodeHash, Parents, Type};
+use mercurial_types::{hash, Changeset, NodeHash, Parents, RepoPath, Time, Type};
 use mercurial_types::manifest::{Entry, Manifest};
 
 #[derive(Debug, Copy, Clone)]
@@ -91,6 +93,82 @@ fn _assert_send<T: Send>(_: &T) {}
 fn _assert_static<T: 'static>(_: &T) {}
 fn _assert_blobstore<T: Blobstore>(_: &T) {}
 
+/// Running totals for a single import, logged periodically and on exit so that a long-running
+/// blobimport against a large repo is observable rather than a silent black box.
+#[derive(Default)]
+struct ImportStats {
+    bytes_written: AtomicUsize,
+    blobs_deduped: AtomicUsize,
+    changesets_copied: AtomicUsize,
+    manifests_copied: AtomicUsize,
+    files_copied: AtomicUsize,
+}
+
+/// Request-scoped state threaded through the whole import pipeline: a logging scope, a shared
+/// stats sink, and a cancellation flag that a SIGINT handler can flip so an in-flight
+/// `core.run(convert)` aborts instead of leaving the process to be killed mid-write.
+#[derive(Clone)]
+struct CoreContext {
+    logger: Logger,
+    stats: Arc<ImportStats>,
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CoreContext {
+    fn new(logger: Logger) -> Self {
+        CoreContext {
+            logger,
+            stats: Arc::new(ImportStats::default()),
+            cancelled: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+
+    fn check_cancelled(&self) -> Result<()> {
+        if self.is_cancelled() {
+            bail!("import cancelled");
+        }
+        Ok(())
+    }
+
+    fn record_bytes_written(&self, bytes: usize) {
+        self.stats.bytes_written.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    fn record_blob_deduped(&self) {
+        self.stats.blobs_deduped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn record_file_copied(&self) {
+        self.stats.files_copied.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn record_manifest_copied(&self) {
+        self.stats.manifests_copied.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // Logs a running summary every `every`-th changeset, so progress on a large import is
+    // visible without spamming the log for every single one.
+    fn record_changeset_copied(&self, seq: u64, every: u64) {
+        self.stats
+            .changesets_copied
+            .fetch_add(1, Ordering::Relaxed);
+
+        if every != 0 && seq % every == 0 {
+            info!(self.logger, "import progress";
+                "changesets" => self.stats.changesets_copied.load(Ordering::Relaxed),
+                "manifests" => self.stats.manifests_copied.load(Ordering::Relaxed),
+                "files" => self.stats.files_copied.load(Ordering::Relaxed),
+                "blobs_deduped" => self.stats.blobs_deduped.load(Ordering::Relaxed),
+                "bytes_written" => self.stats.bytes_written.load(Ordering::Relaxed),
+            );
+        }
+    }
+}
+
 error_chain! {
     links {
         Blobrepo(::blobrepo::Error, ::blobrepo::ErrorKind);
@@ -104,19 +182,99 @@ error_chain! {
     }
 }
 
+/// Map from a filenode to the lowest revlog revision number (`seq`) seen so far to introduce it,
+/// together with the changeset that owns that revision. Because changesets are processed
+/// concurrently (see `buffer_unordered` in `convert`), entries for a given filenode can arrive in
+/// any order; keeping a running minimum here, and only writing it out once every changeset has
+/// been seen (see `flush_linknodes`), makes the winning linknode independent of arrival order.
+/// Writing eagerly on every new minimum instead would race: two `put`s for the same filenode have
+/// no ordering between them under `buffer_unordered`, so the last one to finish - not the one
+/// with the lowest `seq` - would win.
+type LinknodeMap = Arc<Mutex<HashMap<NodeHash, (u64, NodeHash)>>>;
+
+/// Set of manifest entry (file/tree) node hashes already uploaded by some earlier changeset.
+/// History shares entries heavily - most files in a commit are unchanged from their parent - so
+/// without this, `create_changeset` would re-copy every entry reachable from every changeset's
+/// manifest, not just the ones it actually introduces: an O(changesets * files-per-changeset)
+/// write amplification instead of the O(distinct entries) this import actually needs to do.
+type SeenSet = Arc<Mutex<HashSet<NodeHash>>>;
+
+// Record in memory that `csid` (revlog revision `seq`) introduces `filenode`, superseding any
+// higher-numbered revision already recorded as the candidate. Does no I/O; see `flush_linknodes`
+// for where this becomes a blobstore write.
+fn update_linknode(_ctx: &CoreContext, filenode: NodeHash, seq: u64, csid: NodeHash, linknodes: &LinknodeMap) {
+    let mut linknodes = linknodes.lock().expect("linknode map lock poisoned");
+    match linknodes.get(&filenode).cloned() {
+        Some((best_seq, _)) if best_seq <= seq => {}
+        _ => {
+            linknodes.insert(filenode, (seq, csid));
+        }
+    }
+}
+
+/// Write out the winning `linknode:{filenode}` -> `{changeset}` record for every filenode seen
+/// across the whole import. Must only run once every changeset has finished contributing its
+/// candidates to `linknodes` (see `convert`), since a filenode's minimum `seq` - and so its
+/// winning changeset - isn't final until then.
+fn flush_linknodes(
+    ctx: CoreContext,
+    linknode_store: BBlobstore,
+    linknodes: LinknodeMap,
+) -> BoxFuture<(), Error> {
+    let winners: Vec<(NodeHash, NodeHash)> = linknodes
+        .lock()
+        .expect("linknode map lock poisoned")
+        .drain()
+        .map(|(filenode, (_seq, csid))| (filenode, csid))
+        .collect();
+
+    futures::stream::iter_ok(winners)
+        .map(move |(filenode, csid)| {
+            let key = format!("linknode:{}", filenode);
+            let value = Bytes::from(format!("{}", csid));
+            ctx.record_bytes_written(value.len());
+            linknode_store.put(key, value).map_err(Into::into)
+        })
+        .buffer_unordered(100)
+        .for_each(|_| Ok(()))
+        .boxed()
+}
+
 // Copy a single manifest entry into the blobstore
 // TODO: recast as `impl Future<...>` - remove most of these type constraints (which are mostly
 // for BoxFuture)
 // TODO: #[async]
 fn copy_manifest_entry<E>(
+    ctx: CoreContext,
     entry: &Entry<Error = E>,
+    seq: u64,
+    csid: NodeHash,
     blobstore: BBlobstore,
+    linknodes: LinknodeMap,
+    seen: SeenSet,
 ) -> BoxFuture<(), Error>
 where
     Error: From<E>,
     E: Send + 'static,
 {
     let hash = *entry.get_hash();
+    let is_tree = match entry.get_type() {
+        Type::Tree => true,
+        _ => false,
+    };
+
+    // The entry's own node hash already identifies its content and parents, so a hash we've seen
+    // before names a blob some earlier changeset has already durably stored - only its linknode
+    // (tracked regardless of dedup, since every changeset that introduces a filenode is a
+    // candidate for owning it) still needs attention here.
+    let already_uploaded = !seen.lock().expect("seen set lock poisoned").insert(hash);
+
+    update_linknode(&ctx, hash, seq, csid, &linknodes);
+
+    if already_uploaded {
+        ctx.record_blob_deduped();
+        return futures::future::ok(()).boxed();
+    }
 
     let blobfuture = entry.get_raw_content().map_err(Error::from).and_then(
         |blob| {
@@ -139,11 +297,17 @@ where
                 let blobkey = format!("sha1:{}", nodeblob.blob);
                 let nodeblob = bincode::serialize(&nodeblob, bincode::Bounded(4096))
                     .expect("bincode serialize failed");
+                let nodeblob = Bytes::from(nodeblob);
+
+                ctx.record_bytes_written(nodeblob.len() + bytes.len());
+                if is_tree {
+                    ctx.record_manifest_copied();
+                } else {
+                    ctx.record_file_copied();
+                }
 
                 // TODO: blobstore.putv?
-                let node = blobstore
-                    .put(nodekey, Bytes::from(nodeblob))
-                    .map_err(Into::into);
+                let node = blobstore.put(nodekey, nodeblob).map_err(Into::into);
                 let blob = blobstore.put(blobkey, bytes).map_err(Into::into);
 
                 node.join(blob).map(|_| ())
@@ -154,10 +318,13 @@ where
 }
 
 fn get_stream_of_manifest_entries(
+    csid: NodeHash,
     entry: Box<Entry<Error = mercurial::Error>>,
-) -> Box<Stream<Item = Box<Entry<Error = mercurial::Error>>, Error = Error> + Send> {
+) -> Box<Stream<Item = (NodeHash, Box<Entry<Error = mercurial::Error>>), Error = Error> + Send> {
     match entry.get_type() {
-        Type::File | Type::Executable | Type::Symlink => futures::stream::once(Ok(entry)).boxed(),
+        Type::File | Type::Executable | Type::Symlink => {
+            futures::stream::once(Ok((csid, entry))).boxed()
+        }
         Type::Tree => entry
             .get_content()
             .and_then(|content| match content {
@@ -165,27 +332,58 @@ fn get_stream_of_manifest_entries(
                 _ => panic!("should not happened"),
             })
             .flatten_stream()
-            .map(|entry| get_stream_of_manifest_entries(entry))
+            .map(move |entry| get_stream_of_manifest_entries(csid, entry))
             .map_err(Error::from)
             .flatten()
-            .chain(futures::stream::once(Ok(entry)))
+            .chain(futures::stream::once(Ok((csid, entry))))
             .boxed(),
     }
 }
 
-/// Copy a changeset and its manifest into the blobstore
+/// A future that resolves once a changeset - its own changeset/manifest/file blobs, and (once
+/// they resolve) both of its parents - is durably present in the blobstore. Cloning is cheap:
+/// this is the handle callers thread into `create_changeset` for a changeset's children.
+type ChangesetHandle = Shared<BoxFuture<(), Arc<Error>>>;
+
+/// Upload a single manifest entry (recording its linknode candidate in `linknodes`, which
+/// `flush_linknodes` writes out later), exposing the entry's node hash
+/// synchronously so the caller can wire up dependent completions before the upload finishes.
+/// This mirrors the upload-then-commit shape of `UploadHgTreeEntry`/`UploadHgFileEntry`'s
+/// `upload`/`create_changeset`:
+/// the key is known immediately, but the returned future only resolves once the bytes are
+/// durably stored.
+fn upload_entry<E>(
+    ctx: CoreContext,
+    entry: &Entry<Error = E>,
+    seq: u64,
+    csid: NodeHash,
+    blobstore: BBlobstore,
+    linknodes: LinknodeMap,
+    seen: SeenSet,
+) -> (NodeHash, BoxFuture<(), Error>)
+where
+    Error: From<E>,
+    E: Send + 'static,
+{
+    let hash = *entry.get_hash();
+    let upload = copy_manifest_entry(ctx, entry, seq, csid, blobstore, linknodes, seen);
+    (hash, upload)
+}
+
+/// Fetch a changeset and its manifest, and the stream of manifest entries reachable from it.
 ///
 /// The changeset and the manifest are straightforward - we just make literal copies of the
 /// blobs into the blobstore.
 ///
-/// The files are more complex. For each manifest, we generate a stream of entries, then flatten
-/// the entry streams from all changesets into a single stream. Then each entry is filtered
-/// against a set of entries that have already been copied, and any remaining are actually copied.
+/// The files are more complex: we walk the manifest tree and produce a stream of every file and
+/// subtree entry the changeset's manifest references, tagged with `csid` so `create_changeset`
+/// can attribute each upload's linknode (see `update_linknode`) correctly.
 fn copy_changeset(
+    ctx: CoreContext,
     revlog_repo: RevlogRepo,
     blobstore: BBlobstore,
     csid: NodeHash,
-) -> BoxFuture<BoxStream<Box<Entry<Error = mercurial::Error>>, Error>, Error> {
+) -> BoxFuture<BoxStream<(NodeHash, Box<Entry<Error = mercurial::Error>>), Error>, Error> {
     let put = {
         let blobstore = blobstore.clone();
         let csid = csid;
@@ -201,6 +399,8 @@ fn copy_changeset(
     };
 
     let manifest = {
+        let ctx = ctx.clone();
+
         revlog_repo
             .get_changeset_by_nodeid(&csid)
             .from_err()
@@ -221,10 +421,13 @@ fn copy_changeset(
                         let blobkey = format!("sha1:{}", nodeblob.blob);
                         let nodeblob = bincode::serialize(&nodeblob, bincode::Bounded(4096))
                             .expect("bincode serialize failed");
+                        let nodeblob = Bytes::from(nodeblob);
+
+                        ctx.record_bytes_written(nodeblob.len() + bytes.len());
+                        ctx.record_manifest_copied();
+
                         // TODO: blobstore.putv?
-                        let node = blobstore
-                            .put(nodekey, Bytes::from(nodeblob))
-                            .map_err(Into::into);
+                        let node = blobstore.put(nodekey, nodeblob).map_err(Into::into);
                         let putblob = blobstore.put(blobkey, bytes).map_err(Into::into);
 
                         let putmf = putblob.join(node);
@@ -234,9 +437,9 @@ fn copy_changeset(
                                 Error::with_chain(Error::from(err), "Parsing manifest to get list")
                             })
                             .map(|mf| mf.list().map_err(Error::from))
-                            .map(|entry_stream| {
+                            .map(move |entry_stream| {
                                 entry_stream
-                                    .map(|entry| get_stream_of_manifest_entries(entry))
+                                    .map(move |entry| get_stream_of_manifest_entries(csid, entry))
                                     .flatten()
                             })
                             .into_future();
@@ -253,10 +456,360 @@ fn copy_changeset(
     put.join(manifest).map(|(_, fs)| fs.boxed()).boxed()
 }
 
+/// Re-walk `csid`'s manifest purely to repopulate `linknodes` with its candidate `(seq, csid)`
+/// for every filenode it introduces - no blobstore puts, since a changeset `convert` is calling
+/// this for is already known (via `completed_store`) to have had all of its blobs durably copied
+/// on an earlier run.
+///
+/// `convert` skips `create_changeset` - and so `copy_manifest_entry`'s `update_linknode` calls -
+/// entirely for such a changeset, but `flush_linknodes` only writes whatever made it into
+/// `linknodes` *this run*. Left unfixed, a filenode last introduced by a skipped changeset would
+/// either get no `linknode:*` record this run, or - if some later changeset also touches it -
+/// the wrong, higher-seq one would win. Walking the manifest again (cheap: no blob bytes, just
+/// the tree shape) and feeding every entry through `update_linknode` restores the skipped
+/// changeset's candidacy without re-copying anything.
+fn repopulate_linknodes(
+    ctx: CoreContext,
+    revlog_repo: RevlogRepo,
+    linknodes: LinknodeMap,
+    seq: u64,
+    csid: NodeHash,
+) -> BoxFuture<(), Error> {
+    let revlog_repo2 = revlog_repo.clone();
+
+    revlog_repo
+        .get_changeset_by_nodeid(&csid)
+        .from_err()
+        .and_then(move |cs| {
+            let mfid = *cs.manifestid();
+
+            revlog_repo2
+                .get_manifest_blob_by_nodeid(&mfid)
+                .from_err()
+                .and_then(move |blob| {
+                    RevlogManifest::new(revlog_repo2.clone(), blob)
+                        .map_err(|err| {
+                            Error::with_chain(Error::from(err), "Parsing manifest to relink")
+                        })
+                        .map(|mf| mf.list().map_err(Error::from))
+                        .into_future()
+                        .flatten_stream()
+                        .map(move |entry| get_stream_of_manifest_entries(csid, entry))
+                        .flatten()
+                        .for_each(move |(csid, entry)| {
+                            update_linknode(&ctx, *entry.get_hash(), seq, csid, &linknodes);
+                            Ok(())
+                        })
+                })
+        })
+        .map_err(move |err| Error::with_chain(err, format!("Can't relink manifest for cs {}", csid)))
+        .boxed()
+}
+
+/// A single path's change in a `BonsaiChangeset`, relative to the changeset's parents. `None`
+/// (at the `file_changes` map level) means the path was removed. `file_type` here, like `extra`
+/// on `BonsaiChangeset`, keeps its native typed form rather than being frozen as a `Debug`
+/// string, so a reader can parse it back out instead of merely displaying it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct BonsaiFileChange {
+    node: String,
+    file_type: Type,
+    size: u64,
+    // (source path, source filenode) lifted from the filelog's embedded rename metadata - see
+    // `parse_copy_from` - or `None` if this revision carries none.
+    copy_from: Option<(String, String)>,
+}
+
+/// A backend-neutral, parent-relative view of a changeset: for every path touched relative to
+/// its parents, what changed about it. This is what non-hg storage backends are eventually built
+/// on; for now it's derived purely from the revlog data we already have in hand, and stored
+/// alongside the Mercurial form under `bonsai:{csid}` so downstream tooling isn't tied to revlog
+/// semantics. Parents are recorded by their Mercurial changeset id, since this importer has no
+/// separate content-addressed bonsai id scheme yet.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct BonsaiChangeset {
+    parents: Vec<String>,
+    author: String,
+    author_date: Time,
+    message: String,
+    extra: BTreeMap<Vec<u8>, Vec<u8>>,
+    file_changes: BTreeMap<String, Option<BonsaiFileChange>>,
+}
+
+// Recursively list every file/symlink/executable entry reachable from `manifest_id`, keyed by
+// its path. `get_stream_of_manifest_entries`'s tag parameter isn't meaningful here (there is no
+// linknode to record), so `manifest_id` is just threaded through unused.
+fn collect_file_entries(
+    revlog_repo: RevlogRepo,
+    manifest_id: NodeHash,
+) -> BoxFuture<HashMap<RepoPath, Box<Entry<Error = mercurial::Error>>>, Error> {
+    let revlog_repo2 = revlog_repo.clone();
+
+    revlog_repo
+        .get_manifest_blob_by_nodeid(&manifest_id)
+        .from_err()
+        .and_then(move |blob| {
+            RevlogManifest::new(revlog_repo2, blob)
+                .map_err(|err| Error::with_chain(Error::from(err), "Parsing manifest to diff"))
+                .map(|mf| mf.list().map_err(Error::from))
+                .into_future()
+                .flatten_stream()
+                .map(move |entry| get_stream_of_manifest_entries(manifest_id, entry))
+                .flatten()
+                .filter_map(|(_, entry)| match entry.get_type() {
+                    Type::Tree => None,
+                    _ => Some((entry.get_path().clone(), entry)),
+                })
+                .collect()
+        })
+        .map(|pairs: Vec<(RepoPath, Box<Entry<Error = mercurial::Error>>)>| {
+            pairs.into_iter().collect()
+        })
+        .boxed()
+}
+
+// A filelog revision that renames or copies a path carries that fact inline, as a metadata
+// envelope prepended to the raw content: `\x01\n` + `key: value\n` lines + `\x01\n`, before the
+// actual file bytes resume. Pull the `copy`/`copyrev` pair out of that envelope, if present - no
+// filenodes lookup needed, since blobimport works straight off the revlog and never has one.
+fn parse_copy_from(raw: &[u8]) -> Option<(String, String)> {
+    const META_MARKER: &[u8] = b"\x01\n";
+
+    if !raw.starts_with(META_MARKER) {
+        return None;
+    }
+    let rest = &raw[META_MARKER.len()..];
+    let end = rest.windows(META_MARKER.len()).position(|w| w == META_MARKER)?;
+    let meta = String::from_utf8_lossy(&rest[..end]);
+
+    let mut copy_path = None;
+    let mut copy_rev = None;
+    for line in meta.lines() {
+        let mut parts = line.splitn(2, ": ");
+        match (parts.next(), parts.next()) {
+            (Some("copy"), Some(value)) => copy_path = Some(value.to_string()),
+            (Some("copyrev"), Some(value)) => copy_rev = Some(value.to_string()),
+            _ => {}
+        }
+    }
+
+    copy_path.and_then(|path| copy_rev.map(|rev| (path, rev)))
+}
+
+// Fetch the content of a single changed entry so we can record its size and copy-from info (see
+// `parse_copy_from`) in the `BonsaiFileChange`.
+fn file_change_future(
+    path: RepoPath,
+    entry: Box<Entry<Error = mercurial::Error>>,
+) -> BoxFuture<(String, Option<BonsaiFileChange>), Error> {
+    let node = *entry.get_hash();
+    let file_type = entry.get_type();
+
+    entry
+        .get_raw_content()
+        .from_err()
+        .map(move |blob| {
+            let size = blob.size().unwrap_or(0) as u64;
+            let copy_from = blob
+                .into_inner()
+                .and_then(|bytes| parse_copy_from(bytes.as_ref()));
+            (
+                format!("{}", path),
+                Some(BonsaiFileChange {
+                    node: format!("{}", node),
+                    file_type,
+                    size,
+                    copy_from,
+                }),
+            )
+        })
+        .boxed()
+}
+
+/// Compute and store the `BonsaiChangeset` for `csid`: diff its root manifest against each
+/// parent's root manifest to get the set of added/modified/removed paths, lift author/date/
+/// message/extras from the `RevlogChangeset`, and `put` the result under `bonsai:{csid}`.
+fn derive_bonsai_changeset(
+    ctx: CoreContext,
+    revlog_repo: RevlogRepo,
+    blobstore: BBlobstore,
+    csid: NodeHash,
+) -> BoxFuture<(), Error> {
+    if let Err(err) = ctx.check_cancelled() {
+        return futures::future::err(err).boxed();
+    }
+
+    let revlog_repo2 = revlog_repo.clone();
+
+    revlog_repo
+        .get_changeset_by_nodeid(&csid)
+        .from_err()
+        .and_then(move |cs| {
+            let mfid = *cs.manifestid();
+            let parent_ids: Vec<NodeHash> = cs.parents().into_iter().collect();
+            let user = String::from_utf8_lossy(cs.user()).into_owned();
+            let message = String::from_utf8_lossy(cs.comments()).into_owned();
+            let extra = cs.extra().clone();
+            let author_date = cs.time().clone();
+
+            let root_files = collect_file_entries(revlog_repo2.clone(), mfid);
+            let parent_files = futures::future::join_all(parent_ids.iter().cloned().map({
+                let revlog_repo2 = revlog_repo2.clone();
+                move |pid| {
+                    let revlog_repo2 = revlog_repo2.clone();
+                    revlog_repo2
+                        .clone()
+                        .get_changeset_by_nodeid(&pid)
+                        .from_err()
+                        .and_then(move |pcs| collect_file_entries(revlog_repo2, *pcs.manifestid()))
+                }
+            }));
+
+            root_files.join(parent_files).and_then(move |(root, parents)| {
+                let mut seen_paths = HashSet::new();
+                let mut changed = Vec::new();
+                let mut removed = Vec::new();
+
+                for (path, entry) in root {
+                    let unchanged = parents.iter().any(|pfiles| {
+                        pfiles
+                            .get(&path)
+                            .map(|pentry| {
+                                *pentry.get_hash() == *entry.get_hash()
+                                    && pentry.get_type() == entry.get_type()
+                            })
+                            .unwrap_or(false)
+                    });
+                    seen_paths.insert(path.clone());
+                    if !unchanged {
+                        changed.push(file_change_future(path, entry));
+                    }
+                }
+
+                for pfiles in &parents {
+                    for path in pfiles.keys() {
+                        if !seen_paths.contains(path) {
+                            removed.push((format!("{}", path), None));
+                            seen_paths.insert(path.clone());
+                        }
+                    }
+                }
+
+                futures::future::join_all(changed).map(move |mut changes| {
+                    changes.extend(removed);
+
+                    BonsaiChangeset {
+                        parents: parent_ids.iter().map(|p| format!("{}", p)).collect(),
+                        author: user,
+                        author_date,
+                        message,
+                        extra,
+                        file_changes: changes.into_iter().collect(),
+                    }
+                })
+            })
+        })
+        .and_then(move |bonsai| {
+            let key = format!("bonsai:{}", csid);
+            let serialized = bincode::serialize(&bonsai, bincode::Infinite)
+                .expect("bincode serialize of bonsai changeset failed");
+            let serialized = Bytes::from(serialized);
+            ctx.record_bytes_written(serialized.len());
+            blobstore.put(key, serialized).map_err(Into::into)
+        })
+        .boxed()
+}
+
+/// Build a changeset the same way `copy_changeset` does, but gate its visibility: the returned
+/// `ChangesetHandle` only resolves once the changeset's own blobs (changeset, manifest, and
+/// every file/tree entry it references) *and* both `parents` handles have resolved. This means a
+/// crashed import can never leave a changeset reachable from a head whose ancestors, or whose own
+/// entries, are only partially written - the property the raw `copy_changeset` blob copies did
+/// not give us.
+///
+/// Once the changeset's own blobs (but not its parents - see `convert`, which skips calling this
+/// at all for a changeset already marked done) are durably stored, `csid` is recorded in
+/// `completed_store` so a later run of the importer against the same output can skip re-copying
+/// it.
+fn create_changeset(
+    ctx: CoreContext,
+    revlog_repo: RevlogRepo,
+    blobstore: BBlobstore,
+    linknodes: LinknodeMap,
+    seen: SeenSet,
+    completed_store: BBlobstore,
+    cpupool: Arc<CpuPool>,
+    seq: u64,
+    csid: NodeHash,
+    parents: Vec<ChangesetHandle>,
+) -> ChangesetHandle {
+    let bonsai = derive_bonsai_changeset(ctx.clone(), revlog_repo.clone(), blobstore.clone(), csid);
+
+    let own_blobs = copy_changeset(ctx.clone(), revlog_repo, blobstore.clone(), csid)
+        .and_then({
+            let ctx = ctx.clone();
+            let blobstore = blobstore.clone();
+            let linknodes = linknodes.clone();
+            let seen = seen.clone();
+            move |entries| {
+                entries
+                    // Checked before every entry upload, so a SIGINT stops new uploads from
+                    // starting even after `convert` has registered every changeset.
+                    .and_then({
+                        let ctx = ctx.clone();
+                        move |item| ctx.check_cancelled().map(|()| item)
+                    })
+                    .map(move |(csid, entry)| {
+                        let (_, upload) = upload_entry(
+                            ctx.clone(),
+                            &*entry,
+                            seq,
+                            csid,
+                            blobstore.clone(),
+                            linknodes.clone(),
+                            seen.clone(),
+                        );
+                        cpupool.spawn(upload)
+                    })
+                    .buffer_unordered(100)
+                    .for_each(|_| Ok(()))
+            }
+        })
+        .join(bonsai)
+        .and_then(move |_| {
+            ctx.record_changeset_copied(seq, 1000);
+            completed_store
+                .put(format!("done:{}", csid), Bytes::from("1"))
+                .map_err(Into::into)
+        });
+
+    let parents_durable = futures::future::join_all(parents.into_iter().map(|p| {
+        p.map(|_| ()).map_err(move |err| {
+            Error::from(format!(
+                "parent of changeset {} failed to import: {}",
+                csid, err
+            ))
+        })
+    }));
+
+    own_blobs
+        .join(parents_durable)
+        .map(|_| ())
+        .map_err(Arc::new)
+        .boxed()
+        .shared()
+}
+
 fn convert<H>(
+    ctx: CoreContext,
     revlog: RevlogRepo,
+    requirements: RepoRequirements,
     blobstore: BBlobstore,
+    linknode_store: BBlobstore,
     headstore: H,
+    bookmarkstore: BBlobstore,
+    completed_store: BBlobstore,
+    bookmarks: Vec<(String, NodeHash)>,
     cpupool: Arc<CpuPool>,
     logger: &Logger,
 ) -> Result<()>
@@ -265,35 +818,159 @@ where
     H::Error: Into<Error>,
 {
 
+    info!(logger, "repo requirements: {:?}", requirements);
+
     let mut core = tokio_core::reactor::Core::new()?;
 
-    // Generate stream of changesets. For each changeset, save the cs blob, and the manifest blob,
-    // and the files. We get the set of all files as a separate flat stream, and check each one
-    // against a set of seen files
-    let mut seen = HashSet::new();
-    let changesets = revlog.changesets()
+    // Walk changesets in revlog order (parents always precede children), building a map from
+    // each changeset's NodeHash to its ChangesetHandle as we go via `fold`. The fold step itself
+    // only waits on the cheap read of a changeset's parents from the revlog, so the map is built
+    // sequentially and a child never misses a parent that hasn't been registered yet. The handles
+    // are lazy: the blob uploads `create_changeset` describes start once the fold has finished and
+    // `changesets_done` below polls every handle, with each entry upload spawned on `cpupool`.
+    // A child's handle depends on its parents' handles, so a changeset only becomes visible
+    // once it and everything it's reachable from is durably stored.
+    let linknodes: LinknodeMap = Arc::new(Mutex::new(HashMap::new()));
+    // Shared across every changeset's `create_changeset` call so an entry already uploaded while
+    // processing an earlier changeset is recognised and skipped, rather than re-copied.
+    let seen: SeenSet = Arc::new(Mutex::new(HashSet::new()));
+
+    let build_handles = revlog
+        .changesets()
         .map_err(Error::from)
         .enumerate()
-        .map({
-            let blobstore = blobstore.clone();
-            let revlog = revlog.clone();
-            move |(seq, csid)| {
-                info!(logger, "{}: changeset {}", seq, csid);
-                copy_changeset(revlog.clone(), blobstore.clone(), csid)
-            }
-        }) // Stream<Future<Stream<Entry>>>
-        .map(Future::flatten_stream) // Stream<Stream<Entry>>
-        .flatten() // Stream<Entry>
-        .filter(move |entry| { // This is FnMut, with HashSet moved into its closure
-            let key = (entry.get_type(), entry.get_path().clone(), *entry.get_hash());
-            seen.insert(key)
-        })
-        .map({
-            let blobstore = blobstore.clone();
-            move |entry| copy_manifest_entry(&entry, blobstore.clone())
-        })
-        .map(|copy| cpupool.spawn(copy))
-        .buffer_unordered(100);
+        .fold(
+            (HashMap::new(), Vec::new()),
+            {
+                let ctx = ctx.clone();
+                let blobstore = blobstore.clone();
+                let linknodes = linknodes.clone();
+                let seen = seen.clone();
+                let completed_store = completed_store.clone();
+                let revlog = revlog.clone();
+                move |(mut handles, mut all): (HashMap<NodeHash, ChangesetHandle>, Vec<ChangesetHandle>),
+                      (seq, csid)| {
+                    let seq = seq as u64;
+
+                    let ctx = ctx.clone();
+                    let blobstore = blobstore.clone();
+                    let linknodes = linknodes.clone();
+                    let seen = seen.clone();
+                    let completed_store = completed_store.clone();
+                    let revlog = revlog.clone();
+                    let cpupool = cpupool.clone();
+                    let logger = logger.clone();
+
+                    // Checked here once per changeset while it is registered, and again before
+                    // each entry upload in `create_changeset`, so a SIGINT stops new uploads
+                    // from starting.
+                    ctx.check_cancelled()
+                        .into_future()
+                        .and_then(move |()| {
+                            // A changeset already marked done on a previous run doesn't need its
+                            // blobs re-copied - but its children still need a resolved handle to
+                            // join on, so we still register one.
+                            let already_done = completed_store
+                                .get(format!("done:{}", csid))
+                                .map_err(Into::into);
+
+                            revlog
+                                .clone()
+                                .get_changeset_by_nodeid(&csid)
+                                .from_err()
+                                .join(already_done)
+                                .map(move |(cs, already_done)| {
+                                    let parents = cs.parents()
+                                        .into_iter()
+                                        .filter_map(|p| handles.get(&p).cloned())
+                                        .collect::<Vec<_>>();
+
+                                    let handle = if already_done.is_some() {
+                                        info!(logger, "{}: changeset {} already imported, skipping", seq, csid);
+                                        repopulate_linknodes(ctx, revlog, linknodes, seq, csid)
+                                            .map_err(Arc::new)
+                                            .boxed()
+                                            .shared()
+                                    } else {
+                                        info!(logger, "{}: changeset {}", seq, csid);
+                                        create_changeset(
+                                            ctx,
+                                            revlog,
+                                            blobstore,
+                                            linknodes,
+                                            seen,
+                                            completed_store,
+                                            cpupool,
+                                            seq,
+                                            csid,
+                                            parents,
+                                        )
+                                    };
+
+                                    handles.insert(csid, handle.clone());
+                                    all.push(handle);
+
+                                    (handles, all)
+                                })
+                        })
+                }
+            },
+        );
+
+    // Bookmark writes only need the target changeset's handle, so they proceed concurrently
+    // with the rest of the blob copying; a given bookmark just won't resolve until its target
+    // does, so we never point one at a not-yet-written commit.
+    let changesets_and_bookmarks = build_handles.and_then(move |(handles, all)| {
+        let changesets_done = {
+            let ctx = ctx.clone();
+            let linknode_store = linknode_store.clone();
+            let linknodes = linknodes.clone();
+
+            futures::future::join_all(all.into_iter().map(|handle| {
+                handle
+                    .map(|_| ())
+                    .map_err(|err| Error::from(format!("changeset import failed: {}", err)))
+            })).and_then(move |_| {
+                // Every changeset has contributed its candidate linknodes by now, so the minimum
+                // seq (and so the winning changeset) recorded per filenode is final - only now is
+                // it safe to write the results out.
+                flush_linknodes(ctx, linknode_store, linknodes)
+            })
+        };
+
+        let bookmarks_done = futures::future::join_all(bookmarks.into_iter().map(
+            move |(name, target)| {
+                let bookmarkstore = bookmarkstore.clone();
+                let logger = logger.clone();
+
+                match handles.get(&target).cloned() {
+                    Some(handle) => handle
+                        .map_err({
+                            let name = name.clone();
+                            move |err| {
+                                Error::from(format!(
+                                    "bookmark {} target {} failed to import: {}",
+                                    name, target, err
+                                ))
+                            }
+                        })
+                        .and_then(move |_| {
+                            info!(logger, "bookmark {} -> {}", name, target);
+                            bookmarkstore
+                                .put(format!("bookmark:{}", name), Bytes::from(format!("{}", target)))
+                                .map_err(Into::into)
+                        })
+                        .boxed(),
+                    None => futures::future::err(Error::from(format!(
+                        "bookmark {} points at unknown changeset {}",
+                        name, target
+                    ))).boxed(),
+                }
+            },
+        ));
+
+        changesets_done.join(bookmarks_done).map(|_| ())
+    });
 
     let heads = revlog
         .get_heads()
@@ -308,9 +985,10 @@ where
                     move |err| Error::with_chain(err, format!("Failed to create head {}", h))
                 })
         })
-        .buffer_unordered(100);
+        .buffer_unordered(100)
+        .for_each(|_| Ok(()));
 
-    let convert = changesets.merge(heads).for_each(|_| Ok(()));
+    let convert = changesets_and_bookmarks.join(heads);
 
     core.run(convert)?;
 
@@ -322,25 +1000,123 @@ where
     In: AsRef<Path>,
     Out: AsRef<Path>,
 {
+    let ctx = CoreContext::new(logger.clone());
+
+    // Let a single Ctrl-C abort the import cleanly - in-flight puts still get to finish, but no
+    // new entry upload will be started, rather than leaving the process to be killed mid-write.
+    {
+        let cancelled = ctx.cancelled.clone();
+        let logger = logger.clone();
+        ctrlc::set_handler(move || {
+            warn!(logger, "received interrupt, stopping after in-flight work finishes");
+            cancelled.store(true, Ordering::Relaxed);
+        }).chain_err::<_, Error>(|| "Failed to install Ctrl-C handler".into())?;
+    }
+
     let cpupool = Arc::new(CpuPool::new_num_cpus());
 
-    let repo = open_repo(&input)?;
+    let (repo, requirements) = open_repo(&input)?;
     let blobstore = open_blobstore(&output, blobtype)?;
+    let linknode_store = open_linknode_store(&output, blobtype)?;
     let headstore = open_headstore(&output, &cpupool)?;
+    let bookmarkstore = open_bookmarkstore(&output, blobtype)?;
+    let completed_store = open_completed_store(&output, blobtype)?;
+    let bookmarks = read_bookmarks(&input)?;
+
+    convert(
+        ctx,
+        repo,
+        requirements,
+        blobstore,
+        linknode_store,
+        headstore,
+        bookmarkstore,
+        completed_store,
+        bookmarks,
+        cpupool,
+        logger,
+    )
+}
+
+/// The subset of a repo's `.hg/requires` entries that change how we have to read it. Currently
+/// empty, and there is nothing for `convert` to adapt on yet: `lz4revlog` and `generaldelta` are
+/// NOT supported by this importer - no LZ4 decompressor and no delta-base resolution exist in
+/// its revlog read path (that lives in the `mercurial` crate, which this change doesn't touch) -
+/// so `parse_requirements` hard-rejects repos that need either rather than misreading them. This
+/// struct only records that a requirement was *recognized as something to reject*; it doesn't
+/// carry a detected feature set, because no codec work backs it yet. Kept as a struct (rather
+/// than removed outright) so a requirement this importer does learn to act on has somewhere to
+/// go once that work happens.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RepoRequirements {}
+
+/// Requirements this importer understands and can safely ignore.
+const KNOWN_REQUIREMENTS: &[&str] = &["revlogv1", "store", "fncache", "dotencode"];
+
+/// Requirements that change on-disk semantics this importer's revlog reader doesn't implement;
+/// reading a repo with any of these without support would silently produce corrupt blobs, so we
+/// refuse instead. `lz4revlog` would need an LZ4 decompressor wired into the chunk reader, and
+/// `generaldelta` would need the reader to resolve each revision's delta base instead of assuming
+/// the preceding revision - neither decoder exists in this importer, so both are rejected rather
+/// than recorded-but-ignored. Unlike `treemanifest`/`manifestv2`/`largefiles`, which are out of
+/// scope for this importer by design, `lz4revlog`/`generaldelta` support is still an open,
+/// unimplemented piece of work - rejecting them here is a safety fix over the previous silent
+/// accept, not a substitute for actually reading them.
+const REJECTED_REQUIREMENTS: &[&str] = &[
+    "treemanifest",
+    "manifestv2",
+    "largefiles",
+    "lz4revlog",
+    "generaldelta",
+];
+
+fn parse_requirements<P: AsRef<Path>>(root: P) -> Result<RepoRequirements> {
+    let mut path = PathBuf::from(root.as_ref());
+    path.push(".hg");
+    path.push("requires");
+
+    let requirements = RepoRequirements::default();
+
+    // Very old repos predate the requires file entirely; treat them as plain revlogs.
+    if !path.exists() {
+        return Ok(requirements);
+    }
+
+    let contents = ::std::fs::read_to_string(&path)
+        .map_err(Error::from)
+        .chain_err::<_, Error>(|| format!("Failed to read {:?}", path).into())?;
+
+    for requirement in contents.lines().map(str::trim).filter(|l| !l.is_empty()) {
+        if REJECTED_REQUIREMENTS.contains(&requirement) {
+            bail!(
+                "repo at {:?} requires unsupported feature {:?} - refusing to import it rather \
+                 than risk producing corrupt blobs",
+                path,
+                requirement
+            );
+        }
 
-    convert(repo, blobstore, headstore, cpupool, logger)
+        if !KNOWN_REQUIREMENTS.contains(&requirement) {
+            bail!("repo at {:?} requires unknown feature {:?}", path, requirement);
+        }
+    }
+
+    Ok(requirements)
 }
 
-fn open_repo<P: AsRef<Path>>(input: P) -> Result<RevlogRepo> {
+fn open_repo<P: AsRef<Path>>(input: P) -> Result<(RevlogRepo, RepoRequirements)> {
     let mut input = PathBuf::from(input.as_ref());
     if !input.exists() || !input.is_dir() {
         bail!("input {:?} doesn't exist or isn't a dir", input);
     }
+
+    let requirements = parse_requirements(&input)?;
+
     input.push(".hg");
 
     let revlog = RevlogRepo::open(input)?;
 
-    Ok(revlog)
+    Ok((revlog, requirements))
 }
 
 fn open_headstore<P: AsRef<Path>>(heads: P, pool: &Arc<CpuPool>) -> Result<FileHeads<String>> {
@@ -352,6 +1128,104 @@ fn open_headstore<P: AsRef<Path>>(heads: P, pool: &Arc<CpuPool>) -> Result<FileH
     Ok(headstore)
 }
 
+// Opens the linknode sidecar store, alongside the main blobstore. It is keyed the same way
+// (`linknode:{filenode}` -> changeset id) so it reuses whichever backend the main blobstore uses.
+fn open_linknode_store<P: AsRef<Path>>(output: P, ty: BlobstoreType) -> Result<BBlobstore> {
+    let mut output = PathBuf::from(output.as_ref());
+    output.push("linknodes");
+
+    let linknode_store = match ty {
+        BlobstoreType::Files => Fileblob::<_, Bytes>::create(output)
+            .map_err(Error::from)
+            .chain_err::<_, Error>(|| "Failed to open file linknode store".into())?
+            .arced(),
+        BlobstoreType::Rocksdb => Rocksblob::create(output)
+            .map_err(Error::from)
+            .chain_err::<_, Error>(|| "Failed to open rocksdb linknode store".into())?
+            .arced(),
+    };
+
+    Ok(linknode_store)
+}
+
+// Opens the bookmark sidecar store, alongside the main blobstore. Keyed by `bookmark:{name}` ->
+// target changeset id, same shape as the linknode store above.
+fn open_bookmarkstore<P: AsRef<Path>>(output: P, ty: BlobstoreType) -> Result<BBlobstore> {
+    let mut output = PathBuf::from(output.as_ref());
+    output.push("bookmarks");
+
+    let bookmarkstore = match ty {
+        BlobstoreType::Files => Fileblob::<_, Bytes>::create(output)
+            .map_err(Error::from)
+            .chain_err::<_, Error>(|| "Failed to open file bookmark store".into())?
+            .arced(),
+        BlobstoreType::Rocksdb => Rocksblob::create(output)
+            .map_err(Error::from)
+            .chain_err::<_, Error>(|| "Failed to open rocksdb bookmark store".into())?
+            .arced(),
+    };
+
+    Ok(bookmarkstore)
+}
+
+// Opens the completed-changesets sidecar store, alongside the main blobstore. Keyed by
+// `done:{csid}`, written once a changeset's own blobs are durably stored (see
+// `create_changeset`) and consulted by `convert` at the start of each run so a re-import against
+// the same output only copies changesets that weren't finished last time.
+fn open_completed_store<P: AsRef<Path>>(output: P, ty: BlobstoreType) -> Result<BBlobstore> {
+    let mut output = PathBuf::from(output.as_ref());
+    output.push("completed");
+
+    let completed_store = match ty {
+        BlobstoreType::Files => Fileblob::<_, Bytes>::create(output)
+            .map_err(Error::from)
+            .chain_err::<_, Error>(|| "Failed to open file completed-changesets store".into())?
+            .arced(),
+        BlobstoreType::Rocksdb => Rocksblob::create(output)
+            .map_err(Error::from)
+            .chain_err::<_, Error>(|| "Failed to open rocksdb completed-changesets store".into())?
+            .arced(),
+    };
+
+    Ok(completed_store)
+}
+
+// Reads the repo's stock bookmarks file (`.hg/bookmarks`), one `<hex nodeid> <name>` pair per
+// line. Repos with no bookmarks set simply don't have the file.
+fn read_bookmarks<P: AsRef<Path>>(input: P) -> Result<Vec<(String, NodeHash)>> {
+    let mut path = PathBuf::from(input.as_ref());
+    path.push(".hg");
+    path.push("bookmarks");
+
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+
+    let contents = ::std::fs::read_to_string(&path)
+        .map_err(Error::from)
+        .chain_err::<_, Error>(|| format!("Failed to read {:?}", path).into())?;
+
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(|line| {
+            let mut parts = line.splitn(2, ' ');
+            let hash = parts
+                .next()
+                .ok_or_else(|| Error::from(format!("malformed bookmark line {:?}", line)))?;
+            let name = parts
+                .next()
+                .ok_or_else(|| Error::from(format!("malformed bookmark line {:?}", line)))?;
+            let target = hash
+                .parse::<NodeHash>()
+                .map_err(|_| Error::from(format!("malformed bookmark hash {:?}", hash)))?;
+
+            Ok((name.to_string(), target))
+        })
+        .collect()
+}
+
 fn open_blobstore<P: AsRef<Path>>(output: P, ty: BlobstoreType) -> Result<BBlobstore> {
     let mut output = PathBuf::from(output.as_ref());
     output.push("blobs");
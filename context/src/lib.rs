@@ -0,0 +1,101 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! `CoreContext`: the per-request state threaded through every blobstore, filenode, bookmark
+//! and head roundtrip a single client request makes. Lives in its own crate, below `blobrepo`
+//! and `blobstore`, so both can thread it without either depending on the other.
+
+#[macro_use]
+extern crate failure;
+extern crate futures;
+extern crate futures_ext;
+#[macro_use]
+extern crate slog;
+extern crate uuid;
+
+mod semaphore;
+pub use semaphore::{AsyncSemaphore, SemaphorePermit};
+
+use std::sync::Arc;
+
+use slog::Logger;
+use uuid::Uuid;
+
+/// A session id that ties together all the roundtrips a single client request makes, and a
+/// logger scoped to that session. `log_scuba_sample` is the one narrow integration point through
+/// which those roundtrips get tagged; it logs a structured sample via `logger` today, so that it
+/// can be swapped for a real scuba sample builder without touching every call site again.
+///
+/// `blobstore_concurrency`, when set, bounds how many blobstore operations this session may have
+/// in flight at once (see `blobstore::ContextConcurrencyBlobstore`); it is `None` by default, in
+/// which case blobstore access is unthrottled.
+#[derive(Clone)]
+pub struct CoreContext {
+    session: Uuid,
+    logger: Logger,
+    blobstore_concurrency: Option<Arc<AsyncSemaphore>>,
+    background: bool,
+}
+
+impl CoreContext {
+    pub fn new(logger: Logger) -> Self {
+        CoreContext {
+            session: Uuid::new_v4(),
+            logger,
+            blobstore_concurrency: None,
+            background: false,
+        }
+    }
+
+    pub fn new_with_session(session: Uuid, logger: Logger) -> Self {
+        CoreContext {
+            session,
+            logger,
+            blobstore_concurrency: None,
+            background: false,
+        }
+    }
+
+    pub fn session(&self) -> Uuid {
+        self.session
+    }
+
+    pub fn logger(&self) -> &Logger {
+        &self.logger
+    }
+
+    /// Bound the number of concurrent blobstore operations this session may issue. Consuming
+    /// builder, so it composes with the rest of `CoreContext`'s construction.
+    pub fn with_blobstore_concurrency(mut self, limit: usize) -> Self {
+        self.blobstore_concurrency = Some(Arc::new(AsyncSemaphore::new(limit)));
+        self
+    }
+
+    pub fn blobstore_concurrency(&self) -> Option<Arc<AsyncSemaphore>> {
+        self.blobstore_concurrency.clone()
+    }
+
+    /// Mark this session as background/housekeeping work (bulk imports, backfills, derived-data
+    /// jobs, ...). Blobstore layers that maintain bookkeeping on top of durable writes (sync
+    /// queues in a multiplexed backend, for instance) may check `is_background` to skip that
+    /// bookkeeping for writes that don't need it, once all the underlying writes have succeeded.
+    pub fn with_background(mut self) -> Self {
+        self.background = true;
+        self
+    }
+
+    pub fn is_background(&self) -> bool {
+        self.background
+    }
+
+    pub fn log_scuba_sample(&self, op: &'static str, key: &str) {
+        debug!(self.logger, "blobstore roundtrip";
+            "session_uuid" => format!("{}", self.session),
+            "op" => op,
+            "key" => key,
+        );
+    }
+}
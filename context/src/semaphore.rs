@@ -0,0 +1,83 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! A small futures-aware counting semaphore, used to cap how many concurrent operations a
+//! `CoreContext` allows a caller to have in flight (see `blobstore_concurrency`). Permits are
+//! returned via a scope guard (`SemaphorePermit`'s `Drop`), so they are released on error or
+//! cancellation as well as on success.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+use failure::Error;
+use futures::IntoFuture;
+use futures::future::Future;
+use futures::sync::oneshot;
+use futures_ext::{BoxFuture, FutureExt};
+
+struct State {
+    available: usize,
+    waiters: VecDeque<oneshot::Sender<()>>,
+}
+
+pub struct AsyncSemaphore {
+    state: Mutex<State>,
+}
+
+impl AsyncSemaphore {
+    pub fn new(permits: usize) -> Self {
+        AsyncSemaphore {
+            state: Mutex::new(State {
+                available: permits,
+                waiters: VecDeque::new(),
+            }),
+        }
+    }
+
+    /// Acquire a permit, waiting if none are currently available. The returned `SemaphorePermit`
+    /// releases the permit back to `semaphore` when it is dropped.
+    pub fn acquire(semaphore: Arc<AsyncSemaphore>) -> BoxFuture<SemaphorePermit, Error> {
+        let mut state = semaphore.state.lock().expect("semaphore lock poisoned");
+        if state.available > 0 {
+            state.available -= 1;
+            drop(state);
+            return Ok(SemaphorePermit { semaphore }).into_future().boxify();
+        }
+
+        let (tx, rx) = oneshot::channel();
+        state.waiters.push_back(tx);
+        drop(state);
+
+        rx.map(move |_| SemaphorePermit { semaphore })
+            .map_err(|_| Error::from(format_err!("semaphore dropped while waiting for a permit")))
+            .boxify()
+    }
+
+    fn release(&self) {
+        let mut state = self.state.lock().expect("semaphore lock poisoned");
+        match state.waiters.pop_front() {
+            Some(waiter) => {
+                // If the waiter already gave up, just hand the permit to the next one.
+                if waiter.send(()).is_err() {
+                    drop(state);
+                    self.release();
+                }
+            }
+            None => state.available += 1,
+        }
+    }
+}
+
+/// A held permit on an `AsyncSemaphore`; releases it on drop.
+pub struct SemaphorePermit {
+    semaphore: Arc<AsyncSemaphore>,
+}
+
+impl Drop for SemaphorePermit {
+    fn drop(&mut self) {
+        self.semaphore.release();
+    }
+}